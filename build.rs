@@ -78,6 +78,16 @@ fn build_ramdisk() {
         });
     }
 
+    // boot options for the kernel, as the bootloader can't pass a command line
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=KERNEL_CMDLINE");
+    if let Ok(cmdline) = env::var("KERNEL_CMDLINE") {
+        files.push(FileData {
+            filename: "cmdline".to_string(),
+            content: cmdline.into_bytes(),
+        });
+    }
+
     let mut img_data: Vec<u8> = vec![];
 
     img_data.push(files.len() as u8);
@@ -83,8 +83,8 @@ impl Bus {
 
         // Read the IDENTIFY data
         let mut identify_buf = [0u16; 256];
-        for i in 0..256 {
-            identify_buf[i] = self.read_data();
+        for word in identify_buf.iter_mut() {
+            *word = self.read_data();
         }
 
         // Parse model and serial, which are stored as byte-swapped strings
@@ -0,0 +1,75 @@
+//! Boot options. The bootloader has no way to pass a command line, so it is
+//! stored as a `cmdline` file in the ramdisk, taken from the `KERNEL_CMDLINE`
+//! environment variable at build time. Options are separated by whitespace.
+
+use crate::fs;
+use alloc::{string::String, vec};
+use spin::Once;
+
+static CMDLINE: Once<String> = Once::new();
+
+/// Reads the command line from the ramdisk, which must be mounted by now
+pub fn init() {
+    let cmdline = CMDLINE.call_once(|| {
+        let Ok(file) = fs::vfs::open("/initrd/cmdline", fs::file::O_RDONLY) else {
+            return String::new();
+        };
+        let mut buf = vec![0; file.lock().vnode.size()];
+        match fs::vfs::read(&file, &mut buf) {
+            Ok(len) => parse(&buf[..len as usize]),
+            Err(_) => String::new(),
+        }
+    });
+    if !cmdline.is_empty() {
+        println!("Command line: {}", cmdline);
+    }
+}
+
+/// The command line in the contents of the `cmdline` file, which may end in a
+/// newline or hold stray bytes
+fn parse(contents: &[u8]) -> String {
+    String::from_utf8_lossy(contents).trim().into()
+}
+
+/// The whole command line, empty if there wasn't one
+pub fn get() -> &'static str {
+    CMDLINE.get().map_or("", String::as_str)
+}
+
+/// Whether `option` was given on the command line
+pub fn has(option: &str) -> bool {
+    has_option(get(), option)
+}
+
+fn has_option(cmdline: &str, option: &str) -> bool {
+    cmdline.split_whitespace().any(|o| o == option)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_trims_whitespace() {
+        assert_eq!(parse(b"  norandmaps quiet\n"), "norandmaps quiet");
+        assert_eq!(parse(b"\n"), "");
+        assert_eq!(parse(b""), "");
+    }
+
+    #[test]
+    fn parse_replaces_invalid_utf8() {
+        assert_eq!(parse(b"norandmaps \xff"), "norandmaps \u{fffd}");
+    }
+
+    #[test]
+    fn options_are_whole_words() {
+        let cmdline = "quiet\tnorandmaps  debug";
+        assert!(has_option(cmdline, "norandmaps"));
+        assert!(has_option(cmdline, "quiet"));
+        assert!(has_option(cmdline, "debug"));
+        assert!(!has_option(cmdline, "norandmap"));
+        assert!(!has_option(cmdline, "randmaps"));
+        assert!(!has_option(cmdline, ""));
+        assert!(!has_option("", "norandmaps"));
+    }
+}
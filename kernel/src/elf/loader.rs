@@ -5,10 +5,12 @@ use x86_64::{
 };
 
 use crate::memory::allocate_pages;
+use crate::process::vma::{Vma, VmaList, PAGE_SIZE, PROT_EXEC, PROT_READ, PROT_WRITE};
 
 pub(crate) struct UserspaceElfLoader {
     pub(crate) vbase: u64,
     pub(crate) user_page_table_ptr: *mut PageTable,
+    pub(crate) vmas: VmaList, // the segments, as they should be protected once loaded
 }
 
 impl ElfLoader for UserspaceElfLoader {
@@ -32,6 +34,22 @@ impl ElfLoader for UserspaceElfLoader {
                 )
                 .expect("Could not allocate memory");
             }
+
+            let flags = header.flags();
+            let mut prot = 0;
+            if flags.is_read() {
+                prot |= PROT_READ;
+            }
+            if flags.is_write() {
+                prot |= PROT_WRITE;
+            }
+            if flags.is_execute() {
+                prot |= PROT_EXEC;
+            }
+            let start = (self.vbase + header.virtual_addr()) as usize & !(PAGE_SIZE - 1);
+            let end = (self.vbase + header.virtual_addr() + header.mem_size()) as usize;
+            let len = end.next_multiple_of(PAGE_SIZE) - start;
+            self.vmas.insert(Vma::anonymous(start, len, prot, None));
         }
         Ok(())
     }
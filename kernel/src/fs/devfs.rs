@@ -1,6 +1,7 @@
 // Filesystem for storing STDIO for applications
 use crate::fs::errors::Error;
 use crate::fs::vnode::VNode;
+use crate::process::wait_queue::WaitQueue;
 use alloc::sync::Arc;
 use alloc::{
     collections::{BTreeMap, VecDeque},
@@ -34,15 +35,15 @@ impl VNode for DevFs {
     }
 
     fn read(&self, _offset: usize, _buf: &mut [u8]) -> Result<isize, Error> {
-        Err(Error::ReadError) // Cannot read the directory itself as a file
+        Err(Error::IsADirectory) // Cannot read the directory itself as a file
     }
 
-    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<(), Error> {
-        Err(Error::IoError)
+    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::IsADirectory)
     }
 
     fn ioctl(&self, _cmd: u32, _arg: usize) -> Result<(), Error> {
-        todo!()
+        Err(Error::InappropriateIoctl)
     }
 }
 
@@ -63,6 +64,7 @@ impl DevFs {
 #[derive(Debug)]
 pub struct Device {
     data: Mutex<VecDeque<u8>>,
+    readers: WaitQueue,
 }
 
 impl Default for Device {
@@ -75,7 +77,7 @@ impl VNode for Device {
     fn read(&self, _offset: usize, buf: &mut [u8]) -> Result<isize, Error> {
         let mut len_read = 0;
         let mut data = self.data.lock();
-        for item in buf {
+        for item in buf.iter_mut() {
             match data.pop_front() {
                 Some(x) => {
                     len_read += 1;
@@ -84,19 +86,29 @@ impl VNode for Device {
                 None => break,
             }
         }
+        if len_read == 0 && !buf.is_empty() {
+            return Err(Error::WouldBlock);
+        }
         Ok(len_read as isize)
     }
 
-    fn write(&self, _offset: usize, buf: &[u8]) -> Result<(), Error> {
-        let mut data = self.data.lock();
-        for i in buf {
-            data.push_back(*i);
+    fn write(&self, _offset: usize, buf: &[u8]) -> Result<usize, Error> {
+        {
+            let mut data = self.data.lock();
+            for i in buf {
+                data.push_back(*i);
+            }
         }
-        Ok(())
+        self.readers.wake_all();
+        Ok(buf.len())
+    }
+
+    fn read_queue(&self) -> Option<&WaitQueue> {
+        Some(&self.readers)
     }
 
     fn ioctl(&self, _cmd: u32, _arg: usize) -> Result<(), Error> {
-        todo!()
+        Err(Error::InappropriateIoctl)
     }
 }
 
@@ -105,6 +117,7 @@ impl Device {
         // fix me - mutexes
         Device {
             data: Mutex::new(VecDeque::new()),
+            readers: WaitQueue::new(),
         }
     }
 }
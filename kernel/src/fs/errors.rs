@@ -1,4 +1,28 @@
-#[derive(Debug, Clone, Copy)]
+// Error numbers as seen by userspace, matching Linux
+pub const EPERM: usize = 1;
+pub const ENOENT: usize = 2;
+pub const ESRCH: usize = 3;
+pub const EINTR: usize = 4;
+pub const EIO: usize = 5;
+pub const E2BIG: usize = 7;
+pub const ENOEXEC: usize = 8;
+pub const EBADF: usize = 9;
+pub const ECHILD: usize = 10;
+pub const EAGAIN: usize = 11;
+pub const ENOMEM: usize = 12;
+pub const EFAULT: usize = 14;
+pub const EEXIST: usize = 17;
+pub const ENOTDIR: usize = 20;
+pub const EISDIR: usize = 21;
+pub const EINVAL: usize = 22;
+pub const EMFILE: usize = 24;
+pub const ENOTTY: usize = 25;
+pub const EROFS: usize = 30;
+pub const EPIPE: usize = 32;
+pub const ENAMETOOLONG: usize = 36;
+pub const ENOSYS: usize = 38;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Error {
     FileDoesntExist,
     DirDoesntExist,
@@ -6,4 +30,54 @@ pub enum Error {
     ReadError,
     PathSplitError,
     IoError,
+    WouldBlock,
+    PermissionDenied,
+    NoSuchProcess,
+    Interrupted,
+    ArgumentListTooLong,
+    NotExecutable,
+    BadFileDescriptor,
+    NoChildProcess,
+    OutOfMemory,
+    BadAddress,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    InvalidArgument,
+    TooManyOpenFiles,
+    InappropriateIoctl,
+    ReadOnlyFileSystem,
+    NameTooLong,
+    BrokenPipe,
+    NotImplemented,
+}
+
+impl Error {
+    /// The error number reported to userspace for this error
+    pub fn errno(&self) -> usize {
+        match self {
+            Error::FileDoesntExist | Error::DirDoesntExist | Error::DeviceDoesntExist => ENOENT,
+            Error::ReadError | Error::IoError => EIO,
+            Error::PathSplitError | Error::InvalidArgument => EINVAL,
+            Error::WouldBlock => EAGAIN,
+            Error::PermissionDenied => EPERM,
+            Error::NoSuchProcess => ESRCH,
+            Error::Interrupted => EINTR,
+            Error::ArgumentListTooLong => E2BIG,
+            Error::NotExecutable => ENOEXEC,
+            Error::BadFileDescriptor => EBADF,
+            Error::NoChildProcess => ECHILD,
+            Error::OutOfMemory => ENOMEM,
+            Error::BadAddress => EFAULT,
+            Error::AlreadyExists => EEXIST,
+            Error::NotADirectory => ENOTDIR,
+            Error::IsADirectory => EISDIR,
+            Error::TooManyOpenFiles => EMFILE,
+            Error::InappropriateIoctl => ENOTTY,
+            Error::ReadOnlyFileSystem => EROFS,
+            Error::NameTooLong => ENAMETOOLONG,
+            Error::BrokenPipe => EPIPE,
+            Error::NotImplemented => ENOSYS,
+        }
+    }
 }
@@ -14,10 +14,8 @@ impl<IO: Read + Write + Seek + Send + Sync + 'static> VNode for FatFs<IO> {
         let fs_lock = self.fs.lock();
         let dir = fs_lock.root_dir();
 
-        for entry in dir.iter() {
-            if let Ok(e) = entry {
-                ret.push(e.file_name());
-            }
+        for e in dir.iter().flatten() {
+            ret.push(e.file_name());
         }
 
         Ok(ret)
@@ -36,16 +34,28 @@ impl<IO: Read + Write + Seek + Send + Sync + 'static> VNode for FatFs<IO> {
         }))
     }
 
+    fn create(&self, path: &str) -> Result<Arc<dyn VNode>, Error> {
+        let fs_lock = self.fs.lock();
+        let root = fs_lock.root_dir();
+
+        root.create_file(path).map_err(|_| Error::IoError)?;
+
+        Ok(Arc::new(FatFileNode {
+            fs: self.fs.clone(),
+            path: path.to_owned(),
+        }))
+    }
+
     fn read(&self, _offset: usize, _buf: &mut [u8]) -> Result<isize, Error> {
-        Err(Error::ReadError)
+        Err(Error::IsADirectory)
     }
 
-    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<(), Error> {
-        Err(Error::IoError)
+    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::IsADirectory)
     }
 
     fn ioctl(&self, _cmd: u32, _arg: usize) -> Result<(), Error> {
-        Err(Error::IoError)
+        Err(Error::InappropriateIoctl)
     }
 }
 
@@ -69,7 +79,7 @@ impl<IO: Read + Write + Seek + Send + Sync + 'static> VNode for FatFileNode<IO>
         Ok(bytes_read as isize)
     }
 
-    fn write(&self, offset: usize, buf: &[u8]) -> Result<(), Error> {
+    fn write(&self, offset: usize, buf: &[u8]) -> Result<usize, Error> {
         let fs_lock = self.fs.lock();
         let root = fs_lock.root_dir();
         let mut file = root
@@ -80,11 +90,30 @@ impl<IO: Read + Write + Seek + Send + Sync + 'static> VNode for FatFileNode<IO>
             .map_err(|_| Error::IoError)?;
         file.write_all(buf).map_err(|_| Error::IoError)?;
 
-        Ok(())
+        Ok(buf.len())
+    }
+
+    fn size(&self) -> usize {
+        let fs_lock = self.fs.lock();
+        let root = fs_lock.root_dir();
+        root.open_file(&self.path)
+            .and_then(|mut file| file.seek(SeekFrom::End(0)))
+            .map_or(0, |len| len as usize)
+    }
+
+    fn truncate(&self) -> Result<(), Error> {
+        let fs_lock = self.fs.lock();
+        let root = fs_lock.root_dir();
+        let mut file = root
+            .open_file(&self.path)
+            .map_err(|_| Error::FileDoesntExist)?;
+
+        // Truncates at the current position, which is the start of the file
+        file.truncate().map_err(|_| Error::IoError)
     }
 
     fn ioctl(&self, _cmd: u32, _arg: usize) -> Result<(), Error> {
-        Err(Error::IoError)
+        Err(Error::InappropriateIoctl)
     }
 }
 
@@ -3,6 +3,24 @@ use crate::fs::vnode::VNode;
 use alloc::sync::Arc;
 use spin::Mutex;
 
+// Flags for `vfs::open`, with the same values as Linux
+pub const O_RDONLY: usize = 0;
+pub const O_WRONLY: usize = 1;
+pub const O_RDWR: usize = 2;
+pub const O_ACCMODE: usize = 3;
+pub const O_CREAT: usize = 0o100;
+pub const O_EXCL: usize = 0o200;
+pub const O_TRUNC: usize = 0o1000;
+pub const O_APPEND: usize = 0o2000;
+
+/// Where to seek to, relative to the start, the current offset or the end of a file
+#[derive(Debug, Clone, Copy)]
+pub enum SeekFrom {
+    Start(usize),
+    Current(isize),
+    End(isize),
+}
+
 /// An open file description
 pub struct File {
     pub vnode: Arc<dyn VNode>,
@@ -10,6 +28,8 @@ pub struct File {
     pub offset: Mutex<usize>,
     pub readable: bool,
     pub writable: bool,
+    /// Whether every write goes to the end of the file
+    pub append: bool,
 }
 
 impl File {
@@ -19,12 +39,22 @@ impl File {
             offset: Mutex::new(0),
             readable,
             writable,
+            append: false,
+        }
+    }
+
+    /// Opens a vnode with the access mode and `O_APPEND` from `open` flags
+    pub fn from_flags(vnode: Arc<dyn VNode>, flags: usize) -> Self {
+        let mode = flags & O_ACCMODE;
+        File {
+            append: flags & O_APPEND != 0,
+            ..File::new(vnode, mode != O_WRONLY, mode != O_RDONLY)
         }
     }
 
     pub fn read(&self, buf: &mut [u8]) -> Result<isize, Error> {
         if !self.readable {
-            return Err(Error::ReadError);
+            return Err(Error::BadFileDescriptor);
         }
         let mut offset = self.offset.lock();
         let bytes_read = self.vnode.read(*offset, buf)?;
@@ -34,19 +64,29 @@ impl File {
         Ok(bytes_read)
     }
 
-    pub fn write(&self, buf: &[u8]) -> Result<(), Error> {
+    pub fn write(&self, buf: &[u8]) -> Result<usize, Error> {
         if !self.writable {
-            return Err(Error::IoError); // Or a specific WriteError
+            return Err(Error::BadFileDescriptor);
         }
         let mut offset = self.offset.lock();
-        self.vnode.write(*offset, buf)?;
-        *offset += buf.len();
-        Ok(())
+        if self.append {
+            *offset = self.vnode.size();
+        }
+        let bytes_written = self.vnode.write(*offset, buf)?;
+        *offset += bytes_written;
+        Ok(bytes_written)
     }
 
-    pub fn seek(&self, pos: usize) -> Result<usize, Error> {
+    /// Moves the offset used by the next read or write, returning the new offset
+    pub fn seek(&self, pos: SeekFrom) -> Result<usize, Error> {
         let mut offset = self.offset.lock();
-        *offset = pos;
+        let new_offset = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(delta) => offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.vnode.size().checked_add_signed(delta),
+        };
+        // Seeking past the end is allowed, but not before the start
+        *offset = new_offset.ok_or(Error::InvalidArgument)?;
         Ok(*offset)
     }
 
@@ -8,6 +8,9 @@ use alloc::{
 };
 use bootloader_api::info::FrameBuffer;
 
+/// ioctl command that writes the framebuffer's `FrameBufferInfo` to the argument
+pub const FBIO_GET_INFO: u32 = 0;
+
 #[derive(Debug, Clone, Copy)]
 pub struct FrameBufferInfo {
     /// The total size in bytes.
@@ -96,9 +99,7 @@ impl FrameBufferFs {
 
 impl VNode for FrameBufferFs {
     fn dir_entries(&self) -> Result<Vec<String>, Error> {
-        let mut vec = Vec::new();
-        vec.push("0".to_string());
-        Ok(vec)
+        Ok(alloc::vec!["0".to_string()])
     }
 
     fn lookup(&self, path: &str) -> Result<Arc<dyn VNode>, Error> {
@@ -109,7 +110,11 @@ impl VNode for FrameBufferFs {
         }
     }
 
-    fn mmap(&self, offset: usize, _size: usize) -> Result<x86_64::PhysAddr, Error> {
+    fn mmap(&self, offset: usize, size: usize) -> Result<x86_64::PhysAddr, Error> {
+        let end = offset.checked_add(size).ok_or(Error::InvalidArgument)?;
+        if end > self.framebuffer.buffer().len().next_multiple_of(0x1000) {
+            return Err(Error::InvalidArgument);
+        }
         let memory_info = unsafe { crate::memory::MEMORY_INFO.as_ref().ok_or(Error::IoError)? };
         let virt_addr = x86_64::VirtAddr::new(self.framebuffer.buffer().as_ptr() as u64) + offset;
         let phys_addr = crate::memory::translate_addr(virt_addr, memory_info.phys_mem_offset)
@@ -129,26 +134,25 @@ impl VNode for FrameBufferFs {
         Ok(to_read as isize)
     }
 
-    fn write(&self, offset: usize, buf: &[u8]) -> Result<(), Error> {
+    fn write(&self, offset: usize, buf: &[u8]) -> Result<usize, Error> {
         let pointer = self.framebuffer.buffer().as_ptr();
         let fb = unsafe {
             core::slice::from_raw_parts_mut(pointer as *mut u8, self.framebuffer.info().byte_len)
         };
         if offset >= fb.len() {
-            return Ok(());
+            return Ok(0);
         }
         let available = fb.len() - offset;
         let to_write = core::cmp::min(buf.len(), available);
         fb[offset..(offset + to_write)].copy_from_slice(&buf[..to_write]);
 
-        Ok(())
+        Ok(to_write)
     }
 
-    fn ioctl(&self, _cmd: u32, arg: usize) -> Result<(), Error> {
-        let ptr: *mut FrameBufferInfo = arg as *mut FrameBufferInfo;
-        unsafe {
-            (*ptr) = self.generate_info();
+    fn ioctl(&self, cmd: u32, arg: usize) -> Result<(), Error> {
+        match cmd {
+            FBIO_GET_INFO => crate::memory::user::write_to_user(arg, &self.generate_info()),
+            _ => Err(Error::InappropriateIoctl),
         }
-        Ok(())
     }
 }
@@ -73,15 +73,15 @@ impl VNode for InitRd {
     }
 
     fn read(&self, _offset: usize, _buf: &mut [u8]) -> Result<isize, Error> {
-        Err(Error::ReadError)
+        Err(Error::IsADirectory)
     }
 
-    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<(), Error> {
-        Err(Error::IoError)
+    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::IsADirectory)
     }
 
     fn ioctl(&self, _cmd: u32, _arg: usize) -> Result<(), Error> {
-        Err(Error::IoError)
+        Err(Error::InappropriateIoctl)
     }
 }
 
@@ -107,12 +107,16 @@ impl VNode for InitRdNode {
         Ok(to_read as isize)
     }
 
-    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<(), Error> {
-        panic!("Can't write to initrd")
+    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::ReadOnlyFileSystem)
+    }
+
+    fn truncate(&self) -> Result<(), Error> {
+        Err(Error::ReadOnlyFileSystem)
     }
 
     fn ioctl(&self, _cmd: u32, _arg: usize) -> Result<(), Error> {
-        Err(Error::IoError)
+        Err(Error::InappropriateIoctl)
     }
 }
 
@@ -5,6 +5,8 @@ pub mod fatfs;
 pub mod file;
 pub mod framebuffer;
 pub mod initrd;
+pub mod pipe;
+pub mod procfs;
 pub mod stdio;
 pub mod vfs;
 pub mod vnode;
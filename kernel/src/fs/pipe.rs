@@ -0,0 +1,129 @@
+// Anonymous pipes for passing data between processes
+use crate::fs::errors::Error;
+use crate::fs::vnode::VNode;
+use crate::process::wait_queue::WaitQueue;
+use alloc::{collections::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+/// Most bytes a pipe holds before writers have to wait for a reader
+const PIPE_CAPACITY: usize = 4096;
+
+/// The buffer shared by the two ends of a pipe
+struct Pipe {
+    buffer: Mutex<VecDeque<u8>>,
+    reader_open: AtomicBool,
+    writer_open: AtomicBool,
+    readers: WaitQueue,
+    writers: WaitQueue,
+}
+
+/// Creates a pipe, returning its read and write ends. Each end is closed when
+/// the last file referring to it is dropped.
+pub fn new() -> (Arc<PipeReader>, Arc<PipeWriter>) {
+    let pipe = Arc::new(Pipe {
+        buffer: Mutex::new(VecDeque::with_capacity(PIPE_CAPACITY)),
+        reader_open: AtomicBool::new(true),
+        writer_open: AtomicBool::new(true),
+        readers: WaitQueue::new(),
+        writers: WaitQueue::new(),
+    });
+    (
+        Arc::new(PipeReader { pipe: pipe.clone() }),
+        Arc::new(PipeWriter { pipe }),
+    )
+}
+
+pub struct PipeReader {
+    pipe: Arc<Pipe>,
+}
+
+impl VNode for PipeReader {
+    fn read(&self, _offset: usize, buf: &mut [u8]) -> Result<isize, Error> {
+        let len_read = {
+            let mut buffer = self.pipe.buffer.lock();
+            let len = buf.len().min(buffer.len());
+            for (item, byte) in buf.iter_mut().zip(buffer.drain(..len)) {
+                *item = byte;
+            }
+            len
+        };
+
+        if len_read == 0 && !buf.is_empty() {
+            // An empty pipe with no writer left is the end of the file
+            if !self.pipe.writer_open.load(Ordering::Acquire) {
+                return Ok(0);
+            }
+            return Err(Error::WouldBlock);
+        }
+        self.pipe.writers.wake_all();
+        Ok(len_read as isize)
+    }
+
+    fn read_queue(&self) -> Option<&WaitQueue> {
+        Some(&self.pipe.readers)
+    }
+
+    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::BadFileDescriptor)
+    }
+
+    fn size(&self) -> usize {
+        self.pipe.buffer.lock().len()
+    }
+
+    fn ioctl(&self, _cmd: u32, _arg: usize) -> Result<(), Error> {
+        Err(Error::InappropriateIoctl)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.pipe.reader_open.store(false, Ordering::Release);
+        self.pipe.writers.wake_all();
+    }
+}
+
+pub struct PipeWriter {
+    pipe: Arc<Pipe>,
+}
+
+impl VNode for PipeWriter {
+    fn read(&self, _offset: usize, _buf: &mut [u8]) -> Result<isize, Error> {
+        Err(Error::BadFileDescriptor)
+    }
+
+    fn write(&self, _offset: usize, buf: &[u8]) -> Result<usize, Error> {
+        if !self.pipe.reader_open.load(Ordering::Acquire) {
+            return Err(Error::BrokenPipe);
+        }
+
+        let len_written = {
+            let mut buffer = self.pipe.buffer.lock();
+            let len = buf.len().min(PIPE_CAPACITY - buffer.len());
+            buffer.extend(&buf[..len]);
+            len
+        };
+
+        if len_written == 0 && !buf.is_empty() {
+            return Err(Error::WouldBlock);
+        }
+        self.pipe.readers.wake_all();
+        Ok(len_written)
+    }
+
+    fn write_queue(&self) -> Option<&WaitQueue> {
+        Some(&self.pipe.writers)
+    }
+
+    fn ioctl(&self, _cmd: u32, _arg: usize) -> Result<(), Error> {
+        Err(Error::InappropriateIoctl)
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.pipe.writer_open.store(false, Ordering::Release);
+        self.pipe.readers.wake_all();
+    }
+}
@@ -0,0 +1,319 @@
+// Filesystem exposing processes and kernel state, generated afresh on every read
+use crate::fs::errors::Error;
+use crate::fs::vfs;
+use crate::fs::vnode::VNode;
+use crate::memory::{self, allocator, slab_alloc::BLOCK_SIZES};
+use crate::process::{
+    vma::{Backing, PROT_EXEC, PROT_READ, PROT_WRITE},
+    Process, ThreadState,
+};
+use crate::{cmdline, scheduler, time};
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::fmt::Write;
+
+/// Files at the top level, next to the process directories
+const KERNEL_FILES: &[&str] = &["cmdline", "meminfo", "mounts", "slabinfo", "uptime"];
+/// Files in each process' directory
+const PROCESS_FILES: &[&str] = &["status", "fds", "maps"];
+
+pub struct ProcFs;
+
+impl VNode for ProcFs {
+    fn dir_entries(&self) -> Result<Vec<String>, Error> {
+        let mut ret: Vec<String> = KERNEL_FILES.iter().map(|name| name.to_string()).collect();
+        for pid in scheduler::SCHEDULER.read().process_ids() {
+            ret.push(format!("{pid}"));
+        }
+        Ok(ret)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn VNode>, Error> {
+        let split: Vec<&str> = name.split('/').collect();
+        match split[..] {
+            ["cmdline"] => Ok(Arc::new(ProcFile::new(cmdline))),
+            ["meminfo"] => Ok(Arc::new(ProcFile::new(meminfo))),
+            ["mounts"] => Ok(Arc::new(ProcFile::new(mounts))),
+            ["slabinfo"] => Ok(Arc::new(ProcFile::new(slabinfo))),
+            ["uptime"] => Ok(Arc::new(ProcFile::new(uptime))),
+            [pid] => Ok(Arc::new(ProcessDir {
+                pid: find_process(pid)?,
+            })),
+            [pid, file] => {
+                let pid = find_process(pid)?;
+                match file {
+                    "status" => Ok(Arc::new(ProcFile::new(move || process_status(pid)))),
+                    "fds" => Ok(Arc::new(ProcFile::new(move || process_fds(pid)))),
+                    "maps" => Ok(Arc::new(ProcFile::new(move || process_maps(pid)))),
+                    _ => Err(Error::FileDoesntExist),
+                }
+            }
+            _ => Err(Error::FileDoesntExist),
+        }
+    }
+
+    fn read(&self, _offset: usize, _buf: &mut [u8]) -> Result<isize, Error> {
+        Err(Error::IsADirectory)
+    }
+
+    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::IsADirectory)
+    }
+
+    fn ioctl(&self, _cmd: u32, _arg: usize) -> Result<(), Error> {
+        Err(Error::InappropriateIoctl)
+    }
+}
+
+/// Parses a process directory's name, checking the process exists
+fn find_process(name: &str) -> Result<usize, Error> {
+    let pid = name.parse::<usize>().map_err(|_| Error::FileDoesntExist)?;
+    scheduler::SCHEDULER
+        .read()
+        .with_process(pid, |_| pid)
+        .map_err(|_| Error::FileDoesntExist)
+}
+
+pub struct ProcessDir {
+    pid: usize,
+}
+
+impl VNode for ProcessDir {
+    fn dir_entries(&self) -> Result<Vec<String>, Error> {
+        // Check the process is still around
+        scheduler::SCHEDULER.read().with_process(self.pid, |_| ())?;
+        Ok(PROCESS_FILES.iter().map(|name| name.to_string()).collect())
+    }
+
+    fn read(&self, _offset: usize, _buf: &mut [u8]) -> Result<isize, Error> {
+        Err(Error::IsADirectory)
+    }
+
+    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::IsADirectory)
+    }
+
+    fn ioctl(&self, _cmd: u32, _arg: usize) -> Result<(), Error> {
+        Err(Error::InappropriateIoctl)
+    }
+}
+
+type Generator = Box<dyn Fn() -> Result<String, Error> + Send + Sync>;
+
+/// A read-only file whose contents are produced by `generate` each time it is read
+pub struct ProcFile {
+    generate: Generator,
+}
+
+impl ProcFile {
+    fn new(generate: impl Fn() -> Result<String, Error> + Send + Sync + 'static) -> ProcFile {
+        ProcFile {
+            generate: Box::new(generate),
+        }
+    }
+}
+
+impl VNode for ProcFile {
+    fn size(&self) -> usize {
+        (self.generate)().map_or(0, |contents| contents.len())
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<isize, Error> {
+        let contents = (self.generate)()?;
+        let bytes = contents.as_bytes();
+        if offset >= bytes.len() {
+            return Ok(0);
+        }
+        let to_read = core::cmp::min(buf.len(), bytes.len() - offset);
+        buf[..to_read].copy_from_slice(&bytes[offset..offset + to_read]);
+        Ok(to_read as isize)
+    }
+
+    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::ReadOnlyFileSystem)
+    }
+
+    fn truncate(&self) -> Result<(), Error> {
+        Err(Error::ReadOnlyFileSystem)
+    }
+
+    fn ioctl(&self, _cmd: u32, _arg: usize) -> Result<(), Error> {
+        Err(Error::InappropriateIoctl)
+    }
+}
+
+fn cmdline() -> Result<String, Error> {
+    Ok(format!("{}\n", cmdline::get()))
+}
+
+fn meminfo() -> Result<String, Error> {
+    let (frames_used, frames_total) = memory::frame_usage();
+    let (heap_used, heap_total) = allocator::heap_usage();
+    Ok(format!(
+        "MemTotal:\t{} kB\nMemUsed:\t{} kB\nFramesTotal:\t{}\nFramesUsed:\t{}\nFramesFree:\t{}\n\
+         FramesZeroed:\t{}\nHeapTotal:\t{} kB\nHeapUsed:\t{} kB\nHeapFree:\t{} kB\n",
+        frames_total * 4,
+        frames_used * 4,
+        frames_total,
+        frames_used,
+        frames_total - frames_used,
+        memory::zeroed_frames(),
+        heap_total / 1024,
+        heap_used / 1024,
+        (heap_total - heap_used) / 1024,
+    ))
+}
+
+fn mounts() -> Result<String, Error> {
+    let mut ret = String::new();
+    for mountpoint in vfs::mountpoints() {
+        ret.push_str(&mountpoint);
+        ret.push('\n');
+    }
+    Ok(ret)
+}
+
+fn slabinfo() -> Result<String, Error> {
+    let (stats, heap_size) = allocator::heap_stats();
+    let mut ret = format!(
+        "HeapSize:\t{} kB\nHeapMax:\t{} kB\n\nsize\tallocs\tfrees\tlive\tfallback\n",
+        heap_size / 1024,
+        allocator::HEAP_MAX_SIZE / 1024,
+    );
+    for (i, class) in stats.iter().enumerate() {
+        let size = match BLOCK_SIZES.get(i) {
+            Some(size) => format!("{size}"),
+            None => "large".to_string(),
+        };
+        let _ = writeln!(
+            ret,
+            "{size}\t{}\t{}\t{}\t{}",
+            class.allocations, class.frees, class.live_bytes, class.fallback_hits
+        );
+    }
+    Ok(ret)
+}
+
+fn uptime() -> Result<String, Error> {
+    let uptime = time::uptime();
+    Ok(format!(
+        "{}.{:02}\n",
+        uptime.as_secs(),
+        uptime.subsec_millis() / 10
+    ))
+}
+
+/// Describes what a process is doing, in the style of Linux's state letters
+fn process_state(process: &Process, cur_tid: usize) -> &'static str {
+    if process.is_zombie() {
+        "Z (zombie)"
+    } else if process.threads.iter().any(|t| t.thread_id == cur_tid) {
+        "R (running)"
+    } else if process.threads.iter().any(|t| t.state.is_runnable()) {
+        "R (runnable)"
+    } else {
+        "S (sleeping)"
+    }
+}
+
+fn thread_state(state: &ThreadState) -> &'static str {
+    match state {
+        ThreadState::SavedContext(_) | ThreadState::StartingInfo(..) => "runnable",
+        ThreadState::Blocked(_) => "blocked",
+        ThreadState::Exited(_) => "exited",
+    }
+}
+
+fn process_status(pid: usize) -> Result<String, Error> {
+    let scheduler = scheduler::SCHEDULER.read();
+    let cur_tid = scheduler.get_cur_tid();
+    scheduler.with_process(pid, |process| {
+        let mut ret = format!(
+            "Pid:\t{}\nPPid:\t{}\nState:\t{}\nPageTable:\t{:#x}\nBrk:\t{:#x}\nRegions:\t{}\n\
+             SigPnd:\t{:016x}\nSigBlk:\t{:016x}\nThreads:\t{}\n",
+            process.process_id,
+            process.parent_id,
+            process_state(process, cur_tid),
+            process.page_table_phys.as_u64(),
+            process.brk,
+            process.vmas.iter().count(),
+            process.signals.pending,
+            process.signals.blocked,
+            process.threads.len(),
+        );
+        for thread in &process.threads {
+            let _ = writeln!(
+                ret,
+                "Tid {}:\t{}",
+                thread.thread_id,
+                thread_state(&thread.state)
+            );
+        }
+        ret
+    })
+}
+
+fn process_fds(pid: usize) -> Result<String, Error> {
+    let scheduler = scheduler::SCHEDULER.read();
+    let files = scheduler.with_process(pid, |process| {
+        process
+            .file_descriptors
+            .iter()
+            .map(|(&fd, file)| (fd, file.clone()))
+            .collect::<Vec<_>>()
+    })?;
+
+    // Files are locked separately, as reads may hold them while sleeping
+    let mut ret = String::new();
+    for (fd, file) in files {
+        let Some(file) = file.try_lock() else {
+            let _ = writeln!(ret, "{fd}\tbusy");
+            continue;
+        };
+        let mode = match (file.readable, file.writable) {
+            (true, true) => "rw",
+            (true, false) => "r-",
+            (false, true) => "-w",
+            (false, false) => "--",
+        };
+        let append = if file.append { "a" } else { "-" };
+        let _ = writeln!(
+            ret,
+            "{fd}\t{mode}{append}\toffset {}\tsize {}",
+            *file.offset.lock(),
+            file.vnode.size()
+        );
+    }
+    Ok(ret)
+}
+
+fn process_maps(pid: usize) -> Result<String, Error> {
+    scheduler::SCHEDULER.read().with_process(pid, |process| {
+        let mut ret = String::new();
+        for vma in process.vmas.iter() {
+            let flag = |bit, c| if vma.prot & bit != 0 { c } else { '-' };
+            let (shared, offset) = match vma.backing {
+                Backing::Anonymous => ('p', 0),
+                Backing::File { offset, .. } => ('s', offset),
+            };
+            let _ = writeln!(
+                ret,
+                "{:012x}-{:012x} {}{}{}{} {:08x} {}",
+                vma.start,
+                vma.end(),
+                flag(PROT_READ, 'r'),
+                flag(PROT_WRITE, 'w'),
+                flag(PROT_EXEC, 'x'),
+                shared,
+                offset,
+                vma.name.unwrap_or(""),
+            );
+        }
+        ret
+    })
+}
@@ -1,6 +1,7 @@
 // Filesystem for storing STDIO for applications
 use crate::fs::errors::Error;
 use crate::fs::vnode::VNode;
+use crate::process::wait_queue::WaitQueue;
 use alloc::{
     collections::{BTreeMap, VecDeque},
     format,
@@ -53,15 +54,15 @@ impl VNode for StdioFs {
     }
 
     fn read(&self, _offset: usize, _buf: &mut [u8]) -> Result<isize, Error> {
-        Err(Error::ReadError)
+        Err(Error::IsADirectory)
     }
 
-    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<(), Error> {
-        Err(Error::IoError)
+    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::IsADirectory)
     }
 
     fn ioctl(&self, _cmd: u32, _arg: usize) -> Result<(), Error> {
-        Err(Error::IoError)
+        Err(Error::InappropriateIoctl)
     }
 }
 
@@ -87,13 +88,13 @@ impl VNode for ProcessStdioFs {
     }
 
     fn read(&self, _offset: usize, _buf: &mut [u8]) -> Result<isize, Error> {
-        Err(Error::ReadError)
+        Err(Error::IsADirectory)
     }
-    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<(), Error> {
-        Err(Error::IoError)
+    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::IsADirectory)
     }
     fn ioctl(&self, _cmd: u32, _arg: usize) -> Result<(), Error> {
-        Err(Error::IoError)
+        Err(Error::InappropriateIoctl)
     }
 }
 
@@ -103,14 +104,20 @@ struct StdinVNode {
 
 impl VNode for StdinVNode {
     fn read(&self, _offset: usize, buf: &mut [u8]) -> Result<isize, Error> {
-        Ok(self.stdio.read_stdin(buf))
+        match self.stdio.read_stdin(buf) {
+            0 if !buf.is_empty() => Err(Error::WouldBlock),
+            len_read => Ok(len_read),
+        }
+    }
+    fn read_queue(&self) -> Option<&WaitQueue> {
+        Some(&self.stdio.stdin_queue)
     }
-    fn write(&self, _offset: usize, buf: &[u8]) -> Result<(), Error> {
+    fn write(&self, _offset: usize, buf: &[u8]) -> Result<usize, Error> {
         self.stdio.write_stdin(buf);
-        Ok(())
+        Ok(buf.len())
     }
     fn ioctl(&self, _cmd: u32, _arg: usize) -> Result<(), Error> {
-        Err(Error::IoError)
+        Err(Error::InappropriateIoctl)
     }
 }
 
@@ -120,14 +127,20 @@ struct StdoutVNode {
 
 impl VNode for StdoutVNode {
     fn read(&self, _offset: usize, buf: &mut [u8]) -> Result<isize, Error> {
-        Ok(self.stdio.read_stdout(buf))
+        match self.stdio.read_stdout(buf) {
+            0 if !buf.is_empty() => Err(Error::WouldBlock),
+            len_read => Ok(len_read),
+        }
     }
-    fn write(&self, _offset: usize, buf: &[u8]) -> Result<(), Error> {
+    fn read_queue(&self) -> Option<&WaitQueue> {
+        Some(&self.stdio.stdout_queue)
+    }
+    fn write(&self, _offset: usize, buf: &[u8]) -> Result<usize, Error> {
         self.stdio.write_stdout(buf);
-        Ok(())
+        Ok(buf.len())
     }
     fn ioctl(&self, _cmd: u32, _arg: usize) -> Result<(), Error> {
-        Err(Error::IoError)
+        Err(Error::InappropriateIoctl)
     }
 }
 
@@ -149,6 +162,8 @@ impl StdioFs {
 pub struct Stdio {
     stdout: Mutex<VecDeque<u8>>,
     stdin: Mutex<VecDeque<u8>>,
+    stdout_queue: WaitQueue,
+    stdin_queue: WaitQueue,
 }
 
 impl Default for Stdio {
@@ -162,6 +177,8 @@ impl Stdio {
         Stdio {
             stdout: Mutex::new(VecDeque::new()),
             stdin: Mutex::new(VecDeque::new()),
+            stdout_queue: WaitQueue::new(),
+            stdin_queue: WaitQueue::new(),
         }
     }
 
@@ -169,12 +186,12 @@ impl Stdio {
         for i in buf {
             self.stdin.lock().push_back(*i);
         }
+        self.stdin_queue.wake_all();
     }
 
     pub fn write_stdout(&self, buf: &[u8]) {
-        for i in buf {
-            self.stdout.lock().push_back(*i);
-        }
+        self.stdout.lock().extend(buf);
+        self.stdout_queue.wake_all();
     }
 
     pub fn read_stdin(&self, buf: &mut [u8]) -> isize {
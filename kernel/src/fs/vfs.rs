@@ -1,5 +1,5 @@
 use crate::fs::errors::Error;
-use crate::fs::file::File;
+use crate::fs::file::{File, O_ACCMODE, O_CREAT, O_EXCL, O_RDONLY, O_TRUNC};
 use crate::fs::vnode::VNode;
 use alloc::string::String;
 use alloc::sync::Arc;
@@ -55,6 +55,13 @@ pub fn mount(mountpoint: &str, filesystem: Arc<dyn VNode>) {
     fs.sort_by(|a, b| b.mountpoint.len().cmp(&a.mountpoint.len()));
 }
 
+/// The paths everything is mounted at
+pub fn mountpoints() -> Vec<String> {
+    let mut mountpoints: Vec<String> = FS.lock().iter().map(|m| m.mountpoint.clone()).collect();
+    mountpoints.sort();
+    mountpoints
+}
+
 fn resolve_path(path: &str) -> (Option<Arc<dyn VNode>>, String) {
     let canonical = canonicalize_path(path);
     let mut canonical_slash = canonical.clone();
@@ -79,16 +86,29 @@ fn resolve_path(path: &str) -> (Option<Arc<dyn VNode>>, String) {
     (None, String::new())
 }
 
-pub fn open(path: &str) -> Result<Arc<Mutex<File>>, Error> {
+/// Opens a file with `O_*` flags from `fs::file`, creating or truncating it if asked
+pub fn open(path: &str, flags: usize) -> Result<Arc<Mutex<File>>, Error> {
     let (vnode, remaining) = resolve_path(path);
 
     if let Some(device) = vnode {
-        let final_vnode = if remaining.is_empty() {
-            device.clone()
+        let existing = if remaining.is_empty() {
+            Ok(device.clone())
         } else {
-            device.lookup(&remaining)?
+            device.lookup(&remaining)
+        };
+        let final_vnode = match existing {
+            Ok(_) if flags & O_CREAT != 0 && flags & O_EXCL != 0 => {
+                return Err(Error::AlreadyExists)
+            }
+            Ok(vnode) => vnode,
+            Err(Error::FileDoesntExist) if flags & O_CREAT != 0 => device.create(&remaining)?,
+            Err(e) => return Err(e),
         };
-        let file = File::new(final_vnode, true, true);
+
+        if flags & O_TRUNC != 0 && flags & O_ACCMODE != O_RDONLY {
+            final_vnode.truncate()?;
+        }
+        let file = File::from_flags(final_vnode, flags);
         Ok(Arc::new(Mutex::new(file)))
     } else {
         Err(Error::DeviceDoesntExist)
@@ -99,7 +119,7 @@ pub fn read(file: &Arc<Mutex<File>>, buf: &mut [u8]) -> Result<isize, Error> {
     file.lock().read(buf)
 }
 
-pub fn write(file: &Arc<Mutex<File>>, buf: &[u8]) -> Result<(), Error> {
+pub fn write(file: &Arc<Mutex<File>>, buf: &[u8]) -> Result<usize, Error> {
     file.lock().write(buf)
 }
 
@@ -1,4 +1,5 @@
 use crate::fs::errors::Error;
+use crate::process::wait_queue::WaitQueue;
 use alloc::{sync::Arc, vec::Vec};
 
 /// Core interface for a Virtual Node (Inode)
@@ -6,8 +7,8 @@ pub trait VNode: Send + Sync {
     /// Read data from the vnode at the given offset
     fn read(&self, offset: usize, buf: &mut [u8]) -> Result<isize, Error>;
 
-    /// Write data to the vnode at the given offset
-    fn write(&self, offset: usize, buf: &[u8]) -> Result<(), Error>;
+    /// Write data to the vnode at the given offset, returning the number of bytes written
+    fn write(&self, offset: usize, buf: &[u8]) -> Result<usize, Error>;
 
     /// Map a region of the vnode into physical memory
     fn mmap(&self, _offset: usize, _size: usize) -> Result<x86_64::PhysAddr, Error> {
@@ -17,18 +18,38 @@ pub trait VNode: Send + Sync {
     /// Perform a device-specific control operation
     fn ioctl(&self, cmd: u32, arg: usize) -> Result<(), Error>;
 
+    /// The queue to sleep on when `read` reports `Error::WouldBlock`
+    fn read_queue(&self) -> Option<&WaitQueue> {
+        None
+    }
+
+    /// The queue to sleep on when `write` reports `Error::WouldBlock`
+    fn write_queue(&self) -> Option<&WaitQueue> {
+        None
+    }
+
     /// Return the size of the logical file
     fn size(&self) -> usize {
         0
     }
 
+    /// Create a new empty file by name (for directories)
+    fn create(&self, _name: &str) -> Result<Arc<dyn VNode>, Error> {
+        Err(Error::PermissionDenied)
+    }
+
+    /// Discard the contents of the file. Devices without contents ignore this
+    fn truncate(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
     /// Look up a child node by name (for directories)
     fn lookup(&self, _name: &str) -> Result<Arc<dyn VNode>, Error> {
-        Err(Error::DirDoesntExist)
+        Err(Error::NotADirectory)
     }
 
     /// Read directory entries (if this is a directory)
     fn dir_entries(&self) -> Result<Vec<alloc::string::String>, Error> {
-        Err(Error::DirDoesntExist)
+        Err(Error::NotADirectory)
     }
 }
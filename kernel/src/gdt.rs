@@ -12,7 +12,6 @@ use x86_64::{PrivilegeLevel, VirtAddr};
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 pub const PAGE_FAULT_IST_INDEX: u16 = 1;
 pub const GENERAL_PROTECTION_FAULT_IST_INDEX: u16 = 2;
-pub const TIMER_INTERRUPT_INDEX: u16 = 3;
 
 lazy_static! {
     static ref TSS: Mutex<TaskStateSegment> = {
@@ -45,8 +44,6 @@ lazy_static! {
             let stack_start = VirtAddr::from_ptr(unsafe { core::ptr::addr_of!(STACK) });
             stack_start + STACK_SIZE // stack_end
         };
-        tss.interrupt_stack_table[TIMER_INTERRUPT_INDEX as usize] =
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize];
         Mutex::new(tss)
     };
 }
@@ -92,6 +89,10 @@ pub fn init() {
     }
 }
 
+pub fn get_kernel_segments() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.code_selector, GDT.1.data_selector)
+}
+
 pub fn get_usermode_segments() -> (SegmentSelector, SegmentSelector) {
     (GDT.1.user_code_selector, GDT.1.user_data_selector)
 }
@@ -113,6 +114,13 @@ unsafe fn tss_reference() -> &'static TaskStateSegment {
     &*tss_ptr
 }
 
+/// Sets the stack the CPU switches to when entering ring 0 from user mode.
+/// The syscall entry reads the same slot, so this must be updated on every
+/// context switch to point at the incoming process' kernel stack.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    TSS.lock().privilege_stack_table[0] = stack_top;
+}
+
 pub fn tss_address() -> u64 {
     let tss_ptr = &*TSS.lock() as *const TaskStateSegment;
     tss_ptr as u64
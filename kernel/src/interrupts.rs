@@ -1,15 +1,28 @@
-use crate::{gdt, inb, keyboard, outb, process::Context, scheduler};
+use crate::{
+    gdt, inb, keyboard, memory, outb,
+    process::{
+        signal::{self, SignalDelivery},
+        Context,
+    },
+    scheduler, time,
+};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
 use x86_64::{
     instructions::port::Port,
+    registers::control::Cr2,
     structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
 };
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+/// Raised by the kernel itself to switch away from the current thread, e.g.
+/// when it blocks, without counting as a timer tick
+pub const YIELD_VECTOR: u8 = 0x81;
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
@@ -35,21 +48,28 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.divide_error.set_handler_fn(divide_error_naked);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_naked);
+        idt.device_not_available.set_handler_fn(device_not_available_naked);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_naked);
+        idt.alignment_check.set_handler_fn(alignment_check_naked);
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_naked);
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
 
             idt.page_fault
-                .set_handler_fn(page_fault_handler)
+                .set_handler_fn(page_fault_naked)
                 .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
             idt.general_protection_fault
-                .set_handler_fn(general_protection_fault_handler)
+                .set_handler_fn(general_protection_fault_naked)
                 .set_stack_index(gdt::GENERAL_PROTECTION_FAULT_IST_INDEX);
-            idt[InterruptIndex::Timer.as_usize()]
-                .set_handler_fn(timer_handler_naked)
-                .set_stack_index(gdt::TIMER_INTERRUPT_INDEX);
         }
+        // The timer runs on the interrupted process' kernel stack (RSP0 when coming
+        // from user mode) so a context saved mid-syscall is never overwritten
+        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_handler_naked);
+        idt[YIELD_VECTOR as usize].set_handler_fn(yield_handler_naked);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
         idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
         idt
@@ -69,7 +89,7 @@ pub fn init() {
     while inb(0x60) != 0xFA {} /* Wait for ACK from mouse... */
     outb(0x64, 0x20); /* Write 0x20 to port 0x64 */
     let mut config = inb(0x60); /* Read byte from port 0x60 */
-    config = config | 1 << 1; /* Set bit 1*/
+    config |= 1 << 1; /* Set bit 1*/
     outb(0x64, 0x60); /* Write 0x60 to port 0x64 */
     outb(0x60, config); /* Write config byte to port 0x60 */
 
@@ -87,27 +107,100 @@ extern "x86-interrupt" fn double_fault_handler(
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
+extern "C" fn page_fault_handler(context: *mut Context, error_code: u64) -> *const Context {
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+
+    // Writes to pages shared by fork are expected, copy the page and retry
+    if error_code
+        .contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        && memory::handle_cow_fault(Cr2::read())
+    {
+        return core::ptr::null();
+    }
+
+    // Reserved stack, heap and mmap pages get their frame on first touch
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && memory::handle_demand_fault(Cr2::read())
+    {
+        return core::ptr::null();
+    }
+
+    // A bad pointer passed to a syscall makes the copy fail rather than the kernel
+    if !error_code.contains(PageFaultErrorCode::USER_MODE) {
+        let rip = unsafe { (*context).rip };
+        if let Some(fixup) = memory::user::fixup_fault(VirtAddr::new(rip as u64)) {
+            unsafe { (*context).rip = fixup.as_u64() as usize };
+            return core::ptr::null();
+        }
+    }
 
-    println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    crate::hlt_loop();
+    if memory::is_guard_page(Cr2::read()) {
+        return handle_fault(context, "STACK OVERFLOW", signal::SIGSEGV);
+    }
+    handle_fault(context, "PAGE FAULT", signal::SIGSEGV)
 }
 
-extern "x86-interrupt" fn general_protection_fault_handler(
-    stack_frame: InterruptStackFrame,
+extern "C" fn general_protection_fault_handler(
+    context: *mut Context,
+    error_code: u64,
+) -> *const Context {
+    println!("Error Code: {:#x}", error_code);
+    handle_fault(context, "GENERAL PROTECTION FAULT", signal::SIGSEGV)
+}
+
+extern "C" fn stack_segment_fault_handler(
+    context: *mut Context,
     error_code: u64,
-) {
-    println!("EXCEPTION: GENERAL PROTECTION FAULT");
-    println!("Stack Frame: {:#?}", stack_frame);
-    println!("Error: {:?}", error_code);
-    crate::hlt_loop();
+) -> *const Context {
+    println!("Error Code: {:#x}", error_code);
+    handle_fault(context, "STACK SEGMENT FAULT", signal::SIGBUS)
+}
+
+extern "C" fn alignment_check_handler(context: *mut Context, error_code: u64) -> *const Context {
+    println!("Error Code: {:#x}", error_code);
+    handle_fault(context, "ALIGNMENT CHECK", signal::SIGBUS)
+}
+
+extern "C" fn divide_error_handler(context: *mut Context) -> *const Context {
+    handle_fault(context, "DIVIDE ERROR", signal::SIGFPE)
+}
+
+extern "C" fn invalid_opcode_handler(context: *mut Context) -> *const Context {
+    handle_fault(context, "INVALID OPCODE", signal::SIGILL)
+}
+
+extern "C" fn device_not_available_handler(context: *mut Context) -> *const Context {
+    handle_fault(context, "DEVICE NOT AVAILABLE", signal::SIGFPE)
+}
+
+extern "C" fn simd_floating_point_handler(context: *mut Context) -> *const Context {
+    handle_fault(context, "SIMD FLOATING POINT", signal::SIGFPE)
+}
+
+/// Deals with an exception nothing could fix. A fault in the kernel is a bug,
+/// so it panics, but a faulting user process is only killed with `signal` and
+/// the next process is switched to.
+fn handle_fault(context: *const Context, exception: &str, signal: usize) -> *const Context {
+    let context = unsafe { *context };
+    if context.cs & 3 != 3 {
+        panic!("EXCEPTION: {}\n{}", exception, context);
+    }
+
+    {
+        let scheduler = scheduler::SCHEDULER.read();
+        println!(
+            "EXCEPTION: {} in process #{}\n{}",
+            exception,
+            scheduler.get_cur_pid(),
+            context
+        );
+        scheduler.kill_current(signal);
+    }
+
+    // The faulting process can't be returned to
+    run_next_process()
 }
 
 extern "C" fn timer_interrupt_handler(context_addr: *const Context) -> *const Context {
@@ -122,7 +215,44 @@ extern "C" fn timer_interrupt_handler(context_addr: *const Context) -> *const Co
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
 
-    scheduler::SCHEDULER.read().run_next()
+    time::tick();
+    run_next_process()
+}
+
+extern "C" fn yield_interrupt_handler(context_addr: *const Context) -> *const Context {
+    unsafe {
+        scheduler::SCHEDULER
+            .read()
+            .save_current_context(context_addr)
+    };
+
+    run_next_process()
+}
+
+/// Switches to the next runnable process, handling its signals first if it is
+/// going back to user mode
+fn run_next_process() -> *const Context {
+    let scheduler = scheduler::SCHEDULER.read();
+    loop {
+        let context = scheduler.run_next();
+        // Signals are handled on the way back to user mode. Processes switched
+        // out in the middle of a syscall get theirs when the syscall returns.
+        if unsafe { (*context).cs } & 3 != 3 {
+            return context;
+        }
+
+        let mut user_context = unsafe { *context };
+        match scheduler.deliver_signals(&mut user_context) {
+            SignalDelivery::None => return context,
+            SignalDelivery::Handler => {
+                // Saving in place leaves `context` pointing at the rewritten one
+                unsafe { scheduler.save_current_context(&user_context) };
+                return context;
+            }
+            // That process is gone, so pick another
+            SignalDelivery::Terminated => {}
+        }
+    }
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
@@ -224,6 +354,91 @@ macro_rules! interrupt_wrap {
             }
         }
     };
+
+    ($func: ident => $wrapper:ident, $error_code:ty) => {
+        #[naked]
+        pub extern "x86-interrupt" fn $wrapper (_stack_frame: InterruptStackFrame, _error_code: $error_code) {
+            // Naked functions must consist of a single asm! block
+            unsafe{
+                core::arch::asm!(
+                    // Disable interrupts
+                    "cli",
+                    // Swap the error code the CPU pushed for rax, leaving the
+                    // same layout as an interrupt without one
+                    "xchg rax, [rsp]",
+                    "push rbx",
+                    "push rcx",
+                    "push rdx",
+
+                    "push rdi",
+                    "push rsi",
+                    "push rbp",
+                    "push r8",
+
+                    "push r9",
+                    "push r10",
+                    "push r11",
+                    "push r12",
+
+                    "push r13",
+                    "push r14",
+                    "push r15",
+
+                    // First argument in rdi with C calling convention, and
+                    // the error code second
+                    "mov rdi, rsp",
+                    "mov rsi, rax",
+                    // Call the hander function
+                    "call {handler}",
+
+                    // New stack pointer is in RAX
+                    // (C calling convention return value)
+                    "cmp rax, 0",
+                    "je 2f", // If RAX is zero, keep stack
+                    "mov rsp, rax",
+                     "2:",
+
+                    // Pop scratch registers from new stack
+                    "pop r15",
+                    "pop r14",
+                    "pop r13",
+
+                    "pop r12",
+                    "pop r11",
+                    "pop r10",
+                    "pop r9",
+
+                    "pop r8",
+                    "pop rbp",
+                    "pop rsi",
+                    "pop rdi",
+
+                    "pop rdx",
+                    "pop rcx",
+                    "pop rbx",
+                    "pop rax",
+                    // Enable interrupts
+                    "sti",
+                    // Interrupt return
+                    "iretq",
+                    // Note: Getting the handler pointer here using `sym` operand, because
+                    // an `in` operand would clobber a register that we need to save, and we
+                    // can't have two asm blocks
+                    handler = sym $func,
+                    options(noreturn)
+                );
+            }
+        }
+    };
 }
 
 interrupt_wrap!(timer_interrupt_handler => timer_handler_naked);
+interrupt_wrap!(yield_interrupt_handler => yield_handler_naked);
+interrupt_wrap!(page_fault_handler => page_fault_naked, PageFaultErrorCode);
+interrupt_wrap!(general_protection_fault_handler => general_protection_fault_naked, u64);
+interrupt_wrap!(stack_segment_fault_handler => stack_segment_fault_naked, u64);
+interrupt_wrap!(alignment_check_handler => alignment_check_naked, u64);
+interrupt_wrap!(divide_error_handler => divide_error_naked);
+interrupt_wrap!(invalid_opcode_handler => invalid_opcode_naked);
+interrupt_wrap!(device_not_available_handler => device_not_available_naked);
+interrupt_wrap!(simd_floating_point_handler => simd_floating_point_naked);
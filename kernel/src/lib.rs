@@ -1,4 +1,4 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(abi_x86_interrupt)]
 #![feature(const_mut_refs)]
 #![feature(naked_functions)]
@@ -14,6 +14,7 @@ pub mod print;
 
 pub mod ata;
 pub mod ata_pio;
+pub mod cmdline;
 pub mod elf;
 pub mod fs;
 pub mod gdt;
@@ -22,8 +23,10 @@ pub mod keyboard;
 pub mod memory;
 pub mod mouse;
 pub mod process;
+pub mod random;
 pub mod scheduler;
 pub mod syscalls;
+pub mod time;
 
 extern crate alloc;
 
@@ -32,10 +35,12 @@ pub fn init(boot_info: &'static mut BootInfo) {
     x86_64::instructions::interrupts::disable();
     gdt::init();
     interrupts::init();
+    time::init(time::TICK_HZ);
     memory::init(
         boot_info.physical_memory_offset.into_option(),
         &boot_info.memory_regions,
     );
+    random::init();
     ata::init();
     syscalls::init();
     fs::vfs::init();
@@ -44,12 +49,15 @@ pub fn init(boot_info: &'static mut BootInfo) {
     let ramdisk_addr = boot_info.ramdisk_addr.into_option().unwrap() as *const u8;
     let initrd = unsafe { fs::initrd::InitRd::new(ramdisk_addr, boot_info.ramdisk_len as usize) };
     fs::vfs::mount("initrd", Arc::new(initrd));
+    cmdline::init();
 
     let stdiofs = StdioFs::new();
     fs::vfs::mount("stdio", Arc::new(stdiofs));
 
     let devfs = DevFs::new();
     fs::vfs::mount("dev", Arc::new(devfs));
+
+    fs::vfs::mount("proc", Arc::new(fs::procfs::ProcFs));
     mouse::init_mouse();
 
     if let Some(framebuffer) = boot_info.framebuffer.as_mut() {
@@ -66,11 +74,11 @@ pub fn init(boot_info: &'static mut BootInfo) {
     // let file = fs::vfs::open("a:/test-binary").unwrap();
 
     // load  memory manager application and schedule it
-    let file2 = fs::vfs::open("/initrd/window-manager").unwrap();
+    let file2 = fs::vfs::open("/initrd/window-manager", fs::file::O_RDONLY).unwrap();
 
     let sched = &scheduler::SCHEDULER.read();
     // sched.schedule(file);
-    sched.schedule(file2);
+    sched.schedule(file2, &["/initrd/window-manager"], &[]);
 
     println!("{:?}", fs::vfs::list_dir("/stdio/1"));
 
@@ -1,13 +1,17 @@
-use crate::memory::slab_alloc;
+use crate::memory::{self, slab_alloc};
 use x86_64::structures::paging::mapper::MapToError;
 use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
 use x86_64::VirtAddr;
 
-#[global_allocator]
+#[cfg_attr(not(test), global_allocator)]
 static ALLOCATOR: Locked<slab_alloc::SlabAllocator> = Locked::new(slab_alloc::SlabAllocator::new());
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+// The heap lives in the upper half, where its page tables are shared by every
+// process, so pages mapped as it grows are seen by all of them
+pub const HEAP_START: usize = 0x_ffff_c000_0000_0000;
+pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB, mapped up front
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB, the most it can grow to
+pub const HEAP_GROWTH: usize = 64 * 1024; // the least it grows by at once
 
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
@@ -25,6 +29,29 @@ impl<A> Locked<A> {
     }
 }
 
+/// The bytes of kernel heap in use, and its total size. Blocks sitting in the
+/// slab allocator's free lists count as in use.
+pub fn heap_usage() -> (usize, usize) {
+    let allocator = ALLOCATOR.lock();
+    (allocator.used(), allocator.size())
+}
+
+/// Per size class allocation counters, the last entry being for allocations
+/// too big for any class, and the heap's total size
+pub fn heap_stats() -> ([slab_alloc::SizeClassStats; slab_alloc::CLASSES], usize) {
+    let allocator = ALLOCATOR.lock();
+    (allocator.stats(), allocator.size())
+}
+
+/// Maps `size` more bytes onto the end of the heap at `top`, returning false
+/// if that would take it past `HEAP_MAX_SIZE` or there's no memory left
+pub(super) fn grow_heap(top: usize, size: usize) -> bool {
+    let within_limit = top
+        .checked_add(size)
+        .is_some_and(|end| end <= HEAP_START + HEAP_MAX_SIZE);
+    within_limit && memory::map_kernel_pages(VirtAddr::new(top as u64), size as u64)
+}
+
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
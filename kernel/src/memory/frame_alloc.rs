@@ -0,0 +1,353 @@
+use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS: usize = u64::BITS as usize;
+/// How many zeroed frames are kept ready for user mappings
+const ZEROED_POOL_SIZE: usize = 256;
+
+/// A frame allocator keeping one bit per physical frame, set while the frame
+/// is in use. Frames that aren't usable RAM are always marked as in use.
+///
+/// A few frames are kept aside already zeroed, so that handing out a zeroed
+/// frame doesn't usually cost the caller anything. Those count as in use. The
+/// pool is a fixed array because growing a heap buffer could itself need frames.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    total: usize, // usable frames, including the ones holding the bitmap
+    free: usize,
+    next: usize, // where to start looking for a free frame, every one before it is taken
+    phys_mem_offset: VirtAddr,
+    zeroed: [usize; ZEROED_POOL_SIZE], // frame indices
+    zeroed_len: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Create a FrameAllocator from the passed memory map. The bitmap is kept
+    /// at the start of the first usable region big enough for it.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid. The main requirement is that all frames that are marked
+    /// as `USABLE` in it are really unused. All of physical memory must also be
+    /// mapped at `phys_mem_offset`.
+    pub unsafe fn init(memory_map: &'static MemoryRegions, phys_mem_offset: VirtAddr) -> Self {
+        let usable = || {
+            memory_map
+                .iter()
+                .filter(|r| r.kind == MemoryRegionKind::Usable)
+        };
+        let frame_count = usable().map(|r| r.end / FRAME_SIZE).max().unwrap_or(0) as usize;
+        let words = frame_count.div_ceil(BITS);
+        let bitmap_size = (words * 8) as u64;
+
+        let bitmap_region = usable()
+            .find(|r| r.end - r.start.next_multiple_of(FRAME_SIZE) >= bitmap_size)
+            .expect("no room for the frame bitmap");
+        let bitmap_start = bitmap_region.start.next_multiple_of(FRAME_SIZE);
+        let bitmap = unsafe {
+            core::slice::from_raw_parts_mut(
+                (phys_mem_offset + bitmap_start).as_mut_ptr::<u64>(),
+                words,
+            )
+        };
+        bitmap.fill(u64::MAX);
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            total: 0,
+            free: 0,
+            next: 0,
+            phys_mem_offset,
+            zeroed: [0; ZEROED_POOL_SIZE],
+            zeroed_len: 0,
+        };
+        for region in usable() {
+            let first = region.start.div_ceil(FRAME_SIZE) as usize;
+            let last = (region.end / FRAME_SIZE) as usize;
+            for index in first..last {
+                allocator.set_free(index);
+            }
+            allocator.total += last.saturating_sub(first);
+        }
+
+        let bitmap_frames = bitmap_size.div_ceil(FRAME_SIZE) as usize;
+        let first = (bitmap_start / FRAME_SIZE) as usize;
+        for index in first..first + bitmap_frames {
+            allocator.set_used(index);
+        }
+        allocator.next = 0;
+        allocator
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS] & (1 << (index % BITS)) != 0
+    }
+
+    fn set_used(&mut self, index: usize) {
+        debug_assert!(!self.is_used(index));
+        self.bitmap[index / BITS] |= 1 << (index % BITS);
+        self.free -= 1;
+    }
+
+    fn set_free(&mut self, index: usize) {
+        debug_assert!(self.is_used(index));
+        self.bitmap[index / BITS] &= !(1 << (index % BITS));
+        self.free += 1;
+        self.next = self.next.min(index);
+    }
+
+    fn frame(index: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+    }
+
+    /// The number of frames in use, and the number there are
+    pub fn usage(&self) -> (usize, usize) {
+        (self.total - self.free, self.total)
+    }
+
+    /// The number of zeroed frames ready to be handed out
+    pub fn zeroed_count(&self) -> usize {
+        self.zeroed_len
+    }
+
+    fn allocate_from_bitmap(&mut self) -> Option<usize> {
+        // Skip over whole words of used frames at a time
+        let word = (self.next / BITS..self.bitmap.len()).find(|&w| self.bitmap[w] != u64::MAX)?;
+        let index = word * BITS + self.bitmap[word].trailing_ones() as usize;
+        self.set_used(index);
+        self.next = index + 1;
+        Some(index)
+    }
+
+    fn take_zeroed(&mut self) -> Option<usize> {
+        self.zeroed_len = self.zeroed_len.checked_sub(1)?;
+        Some(self.zeroed[self.zeroed_len])
+    }
+
+    fn zero(&self, index: usize) {
+        let virt = self.phys_mem_offset + index as u64 * FRAME_SIZE;
+        unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, FRAME_SIZE as usize) };
+    }
+
+    /// Allocates a frame filled with zeroes, from the pool if there is one
+    /// there, so nothing previously stored in it can leak to its new owner
+    pub fn allocate_zeroed_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(index) = self.take_zeroed() {
+            return Some(Self::frame(index));
+        }
+        let index = self.allocate_from_bitmap()?;
+        self.zero(index);
+        Some(Self::frame(index))
+    }
+
+    /// Zeroes one more free frame for the pool. Returns false if the pool is
+    /// full or there are no free frames left.
+    pub fn refill_zeroed(&mut self) -> bool {
+        if self.zeroed_len == ZEROED_POOL_SIZE {
+            return false;
+        }
+        let Some(index) = self.allocate_from_bitmap() else {
+            return false;
+        };
+        self.zero(index);
+        self.zeroed[self.zeroed_len] = index;
+        self.zeroed_len += 1;
+        true
+    }
+
+    /// Allocates `count` physically contiguous frames, returning the first one.
+    /// Useful for DMA buffers and anything else addressed physically.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        if count == 0 || count > self.free {
+            return None;
+        }
+
+        let frame_count = self.bitmap.len() * BITS;
+        let mut start = self.next;
+        while start + count <= frame_count {
+            match (start..start + count)
+                .rev()
+                .find(|&index| self.is_used(index))
+            {
+                // Nothing before a used frame can start a long enough run
+                Some(used) => start = used + 1,
+                None => {
+                    for index in start..start + count {
+                        self.set_used(index);
+                    }
+                    if start == self.next {
+                        self.next = start + count;
+                    }
+                    return Some(Self::frame(start));
+                }
+            }
+        }
+        None
+    }
+
+    /// Gives back `count` frames from `start`, as allocated by `allocate_contiguous`
+    ///
+    /// # Safety
+    ///
+    /// The frames must not be used anymore once they have been given back.
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let first = (start.start_address().as_u64() / FRAME_SIZE) as usize;
+        for index in first..first + count {
+            self.set_free(index);
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        // The zeroed pool is the last resort, it's wasted on callers that
+        // overwrite the frame anyway
+        let index = self.allocate_from_bitmap().or_else(|| self.take_zeroed())?;
+        Some(Self::frame(index))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.set_free((frame.start_address().as_u64() / FRAME_SIZE) as usize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{boxed::Box, vec, vec::Vec};
+    use bootloader_api::info::MemoryRegion;
+
+    /// 128 frames of "physical memory": frame 0 and 32-47 are reserved, the
+    /// rest is usable. The bitmap ends up in frame 1.
+    fn allocator() -> (BitmapFrameAllocator, VirtAddr) {
+        let memory = vec![0xaau8; 128 * FRAME_SIZE as usize].leak();
+        let phys_mem_offset = VirtAddr::from_ptr(memory.as_ptr());
+        let region = |start, end, kind| MemoryRegion { start, end, kind };
+        let regions: &'static mut [MemoryRegion] = Box::leak(Box::new([
+            region(0x0, 0x1000, MemoryRegionKind::Bootloader),
+            region(0x1000, 0x20000, MemoryRegionKind::Usable),
+            region(0x20000, 0x30000, MemoryRegionKind::Bootloader),
+            region(0x30000, 0x80000, MemoryRegionKind::Usable),
+        ]));
+        let memory_map: &'static MemoryRegions = Box::leak(Box::new(regions.into()));
+        let allocator = unsafe { BitmapFrameAllocator::init(memory_map, phys_mem_offset) };
+        (allocator, phys_mem_offset)
+    }
+
+    fn index(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / FRAME_SIZE) as usize
+    }
+
+    #[test]
+    fn init_counts_usable_frames_and_reserves_the_bitmap() {
+        let (mut allocator, _) = allocator();
+        assert_eq!(allocator.usage(), (1, 111));
+        assert_eq!(allocator.allocate_frame().map(index), Some(2));
+    }
+
+    #[test]
+    fn only_usable_frames_are_handed_out() {
+        let (mut allocator, _) = allocator();
+        let frames: Vec<usize> = core::iter::from_fn(|| allocator.allocate_frame())
+            .map(index)
+            .collect();
+        let expected: Vec<usize> = (2..32).chain(48..128).collect();
+        assert_eq!(frames, expected);
+        assert_eq!(allocator.usage(), (111, 111));
+    }
+
+    #[test]
+    fn freed_frames_are_reused() {
+        let (mut allocator, _) = allocator();
+        let a = allocator.allocate_frame().unwrap();
+        let b = allocator.allocate_frame().unwrap();
+        unsafe { allocator.deallocate_frame(a) };
+        assert_eq!(allocator.usage(), (2, 111));
+        assert_eq!(allocator.allocate_frame(), Some(a));
+        assert_ne!(allocator.allocate_frame(), Some(b));
+    }
+
+    #[test]
+    fn frames_past_the_first_word_are_found() {
+        let (mut allocator, _) = allocator();
+        // Frames 2-31 and 48-85, which runs into the bitmap's second word
+        for _ in 0..68 {
+            allocator.allocate_frame().unwrap();
+        }
+        let frame = allocator.allocate_frame().unwrap();
+        assert_eq!(index(frame), 86);
+        unsafe { allocator.deallocate_frame(frame) };
+        assert_eq!(allocator.allocate_frame(), Some(frame));
+    }
+
+    #[test]
+    fn contiguous_runs_skip_used_and_reserved_frames() {
+        let (mut allocator, _) = allocator();
+        // Frames 2-31 are too few, so the run starts after the reserved ones
+        let first = allocator.allocate_contiguous(40).unwrap();
+        assert_eq!(index(first), 48);
+        // Skipped frames are still there for single allocations
+        assert_eq!(allocator.allocate_frame().map(index), Some(2));
+        // 3-31 is one short now, so this one comes after the first run
+        assert_eq!(allocator.allocate_contiguous(30).map(index), Some(88));
+        assert_eq!(allocator.allocate_contiguous(29).map(index), Some(3));
+        assert_eq!(allocator.usage(), (101, 111));
+    }
+
+    #[test]
+    fn contiguous_runs_that_cannot_fit_fail() {
+        let (mut allocator, _) = allocator();
+        assert_eq!(allocator.allocate_contiguous(0), None);
+        assert_eq!(allocator.allocate_contiguous(81), None);
+        assert_eq!(allocator.allocate_contiguous(112), None);
+        assert_eq!(allocator.usage(), (1, 111));
+    }
+
+    #[test]
+    fn contiguous_runs_are_given_back_whole() {
+        let (mut allocator, _) = allocator();
+        let first = allocator.allocate_contiguous(8).unwrap();
+        unsafe { allocator.deallocate_contiguous(first, 8) };
+        assert_eq!(allocator.usage(), (1, 111));
+        assert_eq!(allocator.allocate_contiguous(8), Some(first));
+    }
+
+    #[test]
+    fn zeroed_frames_are_zeroed() {
+        let (mut allocator, phys_mem_offset) = allocator();
+        let is_zeroed = |frame: PhysFrame| {
+            let virt = phys_mem_offset + frame.start_address().as_u64();
+            let bytes: &[u8] =
+                unsafe { core::slice::from_raw_parts(virt.as_ptr(), FRAME_SIZE as usize) };
+            bytes.iter().all(|&b| b == 0)
+        };
+
+        let frame = allocator.allocate_zeroed_frame().unwrap();
+        assert!(is_zeroed(frame));
+
+        assert!(allocator.refill_zeroed());
+        assert_eq!(allocator.zeroed_count(), 1);
+        assert_eq!(allocator.usage(), (3, 111));
+        let frame = allocator.allocate_zeroed_frame().unwrap();
+        assert!(is_zeroed(frame));
+        assert_eq!(allocator.zeroed_count(), 0);
+    }
+
+    #[test]
+    fn the_zeroed_pool_is_a_last_resort() {
+        let (mut allocator, _) = allocator();
+        while allocator.refill_zeroed() {}
+        // Every free frame fits in the pool
+        assert_eq!(allocator.zeroed_count(), 110);
+        assert_eq!(allocator.usage(), (111, 111));
+        assert!(allocator.allocate_frame().is_some());
+        assert_eq!(allocator.zeroed_count(), 109);
+    }
+}
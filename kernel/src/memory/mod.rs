@@ -1,21 +1,51 @@
 pub mod allocator;
+pub mod frame_alloc;
 pub mod slab_alloc;
+pub mod user;
 
 use core::arch::asm;
 
-use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use alloc::collections::BTreeMap;
+use bootloader_api::info::MemoryRegions;
+use frame_alloc::BitmapFrameAllocator;
+use spin::Mutex;
 use x86_64::{
+    registers::control::{Cr0, Cr0Flags},
     structures::paging::{
-        mapper::{MapToError, UnmapError},
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
-        Size4KiB,
+        mapper::{MapToError, MappedFrame, TranslateResult, UnmapError},
+        page_table::PageTableEntry,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB, Translate,
     },
     PhysAddr, VirtAddr,
 };
 
+/// Marks a user page that is shared read-only after a fork and must be copied
+/// before it is written to
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+/// Marks a user page that maps device memory (e.g. the framebuffer) rather than
+/// a frame owned by the process. These pages stay shared across a fork.
+pub const DEVICE_MEMORY: PageTableFlags = PageTableFlags::BIT_10;
+/// Marks a user page that `mprotect` made inaccessible. It loses
+/// `USER_ACCESSIBLE`, but is otherwise still treated as a user page.
+pub const NO_ACCESS: PageTableFlags = PageTableFlags::BIT_11;
+/// Marks a user page that is reserved but has no frame yet, its entry being
+/// left not present. One is allocated when it's first touched.
+pub const RESERVED: PageTableFlags = PageTableFlags::BIT_52;
+/// Marks the never mapped page below a stack, so overflowing it can be told
+/// apart from other bad accesses
+pub const GUARD_PAGE: PageTableFlags = PageTableFlags::BIT_53;
+
+/// Number of page tables mapping each shared user frame, keyed by physical
+/// address. Frames that are not listed are mapped exactly once.
+static FRAME_REFS: Mutex<BTreeMap<u64, usize>> = Mutex::new(BTreeMap::new());
+
+/// The first level 4 entry of the upper half, where only the kernel is mapped
+const KERNEL_HALF_START: usize = 256;
+
 pub struct MemoryInfo {
     pub phys_mem_offset: VirtAddr,
-    frame_allocator: BootInfoFrameAllocator,
+    frame_allocator: BitmapFrameAllocator,
     kernel_l4_table: &'static mut PageTable,
 }
 
@@ -25,10 +55,15 @@ pub static mut MEMORY_INFO: Option<MemoryInfo> = None;
 pub fn init(physical_memory_offset: Option<u64>, memory_regions: &'static MemoryRegions) {
     let phys_mem_offset = VirtAddr::new(physical_memory_offset.unwrap());
     let mut mapper = unsafe { init_page_table(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(memory_regions) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(memory_regions, phys_mem_offset) };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
 
+    // Make ring 0 respect read-only pages too, so the kernel writing into a
+    // copy-on-write user page takes the fault that un-shares it
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+
     let level_4_table = unsafe { active_level_4_table(phys_mem_offset) };
 
     unsafe {
@@ -140,21 +175,61 @@ fn create_empty_pagetable() -> (*mut PageTable, PhysAddr) {
     (page_table_ptr, phys)
 }
 
-fn copy_pagetables(level_4_table: &PageTable) -> (*mut PageTable, PhysAddr) {
+/// Records that one more page table maps `frame`
+fn share_frame(refs: &mut BTreeMap<u64, usize>, frame: PhysFrame) {
+    *refs.entry(frame.start_address().as_u64()).or_insert(1) += 1;
+}
+
+/// Records that one page table no longer maps `frame`, returning true if
+/// that was the last mapping and the frame is now unused
+fn unshare_frame(refs: &mut BTreeMap<u64, usize>, frame: PhysFrame) -> bool {
+    let addr = frame.start_address().as_u64();
+    match refs.get_mut(&addr) {
+        Some(count) => {
+            *count -= 1;
+            if *count <= 1 {
+                refs.remove(&addr);
+            }
+            false
+        }
+        None => true,
+    }
+}
+
+fn copy_pagetables(level_4_table: &mut PageTable) -> (*mut PageTable, PhysAddr) {
     // Create a new level 4 pagetable
     let (table_ptr, table_physaddr) = create_empty_pagetable();
     let table = unsafe { &mut *table_ptr };
 
     fn copy_pages_rec(
         physical_memory_offset: VirtAddr,
-        from_table: &PageTable,
+        from_table: &mut PageTable,
         to_table: &mut PageTable,
         level: u16,
     ) {
-        for (i, entry) in from_table.iter().enumerate() {
+        for (i, entry) in from_table.iter_mut().enumerate() {
             if !entry.is_unused() {
-                if (level == 1) || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                if level == 4 && i >= KERNEL_HALF_START {
+                    // The upper half only maps the kernel, and its tables are shared
+                    // by every page table so that e.g. heap growth shows up in all
+                    to_table[i].set_addr(entry.addr(), entry.flags());
+                } else if (level == 1) || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
                     // Maps a frame, not a page table
+                    let flags = entry.flags();
+                    // Reserved pages have no frame yet, each side gets its own later
+                    if flags.intersects(PageTableFlags::USER_ACCESSIBLE | NO_ACCESS)
+                        && !flags.intersects(DEVICE_MEMORY | RESERVED)
+                    {
+                        // User frames are shared between both tables. Writable ones become
+                        // read-only in both, and are copied by whichever side writes first
+                        if flags.intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE) {
+                            let cow_flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                            entry.set_flags(cow_flags);
+                        }
+                        if let Ok(frame) = entry.frame() {
+                            share_frame(&mut FRAME_REFS.lock(), frame);
+                        }
+                    }
                     to_table[i].set_addr(entry.addr(), entry.flags());
                 } else {
                     // Create a new table at level - 1
@@ -167,7 +242,7 @@ fn copy_pagetables(level_4_table: &PageTable) -> (*mut PageTable, PhysAddr) {
                     // Get reference to the input level-1 table
                     let from_table_m1 = {
                         let virt = physical_memory_offset + entry.addr().as_u64();
-                        unsafe { &*virt.as_ptr() }
+                        unsafe { &mut *virt.as_mut_ptr() }
                     };
 
                     // Copy level-1 entries
@@ -197,13 +272,335 @@ pub fn create_new_user_pagetable() -> (*mut PageTable, PhysAddr) {
     (user_page_table_ptr, user_page_table_physaddr)
 }
 
-pub fn copy_user_pagetable(pagetable: &PageTable) -> (*mut PageTable, PhysAddr) {
+/// Copies a user address space for fork. Writable user pages are marked
+/// copy-on-write in both the original and the copy.
+pub fn copy_user_pagetable(pagetable: &mut PageTable) -> (*mut PageTable, PhysAddr) {
     // Copy pages
     let (user_page_table_ptr, user_page_table_physaddr) = copy_pagetables(pagetable);
 
+    // The original is usually the active table, so drop its stale writable entries
+    x86_64::instructions::tlb::flush_all();
+
     (user_page_table_ptr, user_page_table_physaddr)
 }
 
+/// Frees a process' page table once nothing runs on it anymore, along with
+/// the user frames it maps. Device memory and the kernel's own frames are
+/// left alone, and shared frames are only freed by the last table mapping them.
+pub fn free_user_pagetable(page_table_phys: PhysAddr) {
+    use x86_64::registers::control::Cr3;
+
+    // Don't pull the tables out from under the CPU
+    if Cr3::read().0.start_address() == page_table_phys {
+        switch_to_kernel_pagetable();
+    }
+
+    // Tables in the lower half are the process' own copies, including those
+    // mapping parts of the kernel there, so all of them are freed. The upper
+    // half's are shared (see `copy_pagetables`) and left alone.
+    fn free_pages_rec(
+        memory_info: &mut MemoryInfo,
+        refs: &mut BTreeMap<u64, usize>,
+        table: &PageTable,
+        level: u16,
+    ) {
+        let count = if level == 4 { KERNEL_HALF_START } else { 512 };
+        for entry in table.iter().take(count).filter(|entry| !entry.is_unused()) {
+            let flags = entry.flags();
+            if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+                let owned = flags.intersects(PageTableFlags::USER_ACCESSIBLE | NO_ACCESS)
+                    && !flags.contains(DEVICE_MEMORY);
+                if let (true, Ok(frame)) = (owned, entry.frame()) {
+                    if unshare_frame(refs, frame) {
+                        unsafe { memory_info.frame_allocator.deallocate_frame(frame) };
+                    }
+                }
+            } else {
+                let virt = memory_info.phys_mem_offset + entry.addr().as_u64();
+                free_pages_rec(memory_info, refs, unsafe { &*virt.as_ptr() }, level - 1);
+                let frame = PhysFrame::containing_address(entry.addr());
+                unsafe { memory_info.frame_allocator.deallocate_frame(frame) };
+            }
+        }
+    }
+
+    let memory_info = unsafe { MEMORY_INFO.as_mut().unwrap() };
+    let virt = memory_info.phys_mem_offset + page_table_phys.as_u64();
+    let level_4_table: &PageTable = unsafe { &*virt.as_ptr() };
+    free_pages_rec(memory_info, &mut FRAME_REFS.lock(), level_4_table, 4);
+    unsafe {
+        memory_info
+            .frame_allocator
+            .deallocate_frame(PhysFrame::containing_address(page_table_phys))
+    };
+}
+
+/// Resolves a write fault on a copy-on-write page in the active page table by
+/// giving it a private writable frame. Returns false if `addr` is not a
+/// copy-on-write page, i.e. the fault is a genuine access violation.
+pub fn handle_cow_fault(addr: VirtAddr) -> bool {
+    let memory_info = unsafe { MEMORY_INFO.as_mut().unwrap() };
+    let level_4_table = unsafe { active_level_4_table(memory_info.phys_mem_offset) }.0;
+    let mut mapper = unsafe { OffsetPageTable::new(level_4_table, memory_info.phys_mem_offset) };
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let (frame, flags) = match mapper.translate(addr) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } => (frame, flags),
+        _ => return false,
+    };
+    if !flags.contains(COPY_ON_WRITE) {
+        return false;
+    }
+    let new_flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+    let mut refs = FRAME_REFS.lock();
+    if refs.contains_key(&frame.start_address().as_u64()) {
+        // Still shared, so this page table gets its own copy of the frame
+        let Some(new_frame) = memory_info.frame_allocator.allocate_frame() else {
+            return false;
+        };
+        unsafe {
+            let src: *const u8 =
+                (memory_info.phys_mem_offset + frame.start_address().as_u64()).as_ptr();
+            let dst: *mut u8 =
+                (memory_info.phys_mem_offset + new_frame.start_address().as_u64()).as_mut_ptr();
+            core::ptr::copy_nonoverlapping(src, dst, 4096);
+        }
+        unshare_frame(&mut refs, frame);
+
+        let Ok((_, flush)) = mapper.unmap(page) else {
+            return false;
+        };
+        flush.flush();
+        match unsafe { mapper.map_to(page, new_frame, new_flags, &mut memory_info.frame_allocator) }
+        {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => false,
+        }
+    } else {
+        // The other side already took a copy, so the frame can simply be reused
+        match unsafe { mapper.update_flags(page, new_flags) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// Finds the level 1 entry for `addr` in the page table hierarchy at
+/// `level_4_table`, creating the tables on the way there if `create` is set.
+/// Returns `None` if there is no such entry, e.g. under a huge page.
+///
+/// # Safety
+///
+/// `level_4_table` must point to the level 4 table of a valid page table
+/// hierarchy, and the entry must not be used through another reference.
+unsafe fn leaf_entry(
+    level_4_table: *mut PageTable,
+    addr: VirtAddr,
+    create: bool,
+) -> Option<&'static mut PageTableEntry> {
+    let memory_info = unsafe { MEMORY_INFO.as_mut()? };
+    let mut table = unsafe { &mut *level_4_table };
+    for index in [addr.p4_index(), addr.p3_index(), addr.p2_index()] {
+        let entry = &mut table[index];
+        let parent_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        if entry.is_unused() {
+            if !create {
+                return None;
+            }
+            let frame = memory_info.frame_allocator.allocate_frame()?;
+            let virt = memory_info.phys_mem_offset + frame.start_address().as_u64();
+            unsafe { (*virt.as_mut_ptr::<PageTable>()).zero() };
+            entry.set_frame(frame, parent_flags);
+        } else if entry.flags().contains(PageTableFlags::HUGE_PAGE)
+            || !entry.flags().contains(PageTableFlags::PRESENT)
+        {
+            return None;
+        } else if create {
+            // As `map_to` does, so user pages below are reachable
+            entry.set_flags(entry.flags() | parent_flags);
+        }
+        let virt = memory_info.phys_mem_offset + entry.addr().as_u64();
+        table = unsafe { &mut *virt.as_mut_ptr() };
+    }
+    Some(&mut table[addr.p1_index()])
+}
+
+/// Reserves the pages in `size` bytes from `start_addr` in the level_4_table
+/// supplied, to be given a frame with `flags` once they are first touched
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must guarantee that the
+/// passed `level_4_table` must point to the level 4 page table of a valid
+/// page table hierarchy.
+pub unsafe fn reserve_pages(
+    level_4_table: *mut PageTable,
+    start_addr: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let page_range = {
+        let end_addr = start_addr + size - 1u64;
+        let start_page = Page::<Size4KiB>::containing_address(start_addr);
+        let end_page = Page::containing_address(end_addr);
+        Page::range_inclusive(start_page, end_page)
+    };
+
+    for page in page_range {
+        let entry = unsafe { leaf_entry(level_4_table, page.start_address(), true) }
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        if !entry.is_unused() {
+            return Err(MapToError::PageAlreadyMapped(
+                PhysFrame::containing_address(entry.addr()),
+            ));
+        }
+        entry.set_addr(
+            PhysAddr::new(0),
+            (flags - PageTableFlags::PRESENT) | RESERVED,
+        );
+    }
+    Ok(())
+}
+
+/// Leaves the page at `addr` in the level_4_table supplied unmapped for good,
+/// as the guard page below a stack
+///
+/// # Safety
+///
+/// This function is unsafe because the caller must guarantee that the
+/// passed `level_4_table` must point to the level 4 page table of a valid
+/// page table hierarchy.
+pub unsafe fn add_guard_page(level_4_table: *mut PageTable, addr: VirtAddr) {
+    if let Some(entry) = unsafe { leaf_entry(level_4_table, addr, true) } {
+        if entry.is_unused() {
+            entry.set_addr(PhysAddr::new(0), GUARD_PAGE);
+        }
+    }
+}
+
+/// Resolves a fault on a reserved page in the active page table by giving it a
+/// zeroed frame. Returns false if `addr` is not a page that can be touched.
+pub fn handle_demand_fault(addr: VirtAddr) -> bool {
+    let memory_info = unsafe { MEMORY_INFO.as_mut().unwrap() };
+    let level_4_table = unsafe { active_level_4_table(memory_info.phys_mem_offset) }.0;
+    let Some(entry) = (unsafe { leaf_entry(level_4_table, addr, false) }) else {
+        return false;
+    };
+
+    let flags = entry.flags();
+    if flags.contains(PageTableFlags::PRESENT) || !flags.contains(RESERVED) {
+        return false;
+    }
+    // Inaccessible pages wouldn't be any more accessible with a frame
+    if flags.contains(NO_ACCESS) {
+        return false;
+    }
+    let Some(frame) = memory_info.frame_allocator.allocate_zeroed_frame() else {
+        return false;
+    };
+    entry.set_frame(frame, (flags - RESERVED) | PageTableFlags::PRESENT);
+    x86_64::instructions::tlb::flush(addr);
+    true
+}
+
+/// Whether `addr` is in the guard page below a stack in the active page table
+pub fn is_guard_page(addr: VirtAddr) -> bool {
+    let memory_info = unsafe { MEMORY_INFO.as_mut().unwrap() };
+    let level_4_table = unsafe { active_level_4_table(memory_info.phys_mem_offset) }.0;
+    unsafe { leaf_entry(level_4_table, addr, false) }
+        .is_some_and(|entry| entry.flags().contains(GUARD_PAGE))
+}
+
+/// The flags of the user page at `addr` in the active page table, if it is
+/// mapped or reserved
+pub(super) fn user_page_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    let memory_info = unsafe { MEMORY_INFO.as_mut()? };
+    let level_4_table = unsafe { active_level_4_table(memory_info.phys_mem_offset) }.0;
+    let flags = unsafe { leaf_entry(level_4_table, addr, false) }?.flags();
+    flags
+        .intersects(PageTableFlags::PRESENT | RESERVED)
+        .then_some(flags)
+}
+
+/// Changes the access the user has to the pages in `len` bytes from `start` in
+/// the active page table. Frames still shared since a fork become copy-on-write
+/// rather than writable, so that the sharing stays invisible.
+pub fn protect_user_pages(start: VirtAddr, len: u64, accessible: bool, writable: bool) {
+    let memory_info = unsafe { MEMORY_INFO.as_mut().unwrap() };
+    let level_4_table = unsafe { active_level_4_table(memory_info.phys_mem_offset) }.0;
+
+    let page_range = {
+        let start_page = Page::<Size4KiB>::containing_address(start);
+        let end_page = Page::containing_address(start + len - 1u64);
+        Page::range_inclusive(start_page, end_page)
+    };
+
+    let refs = FRAME_REFS.lock();
+    for page in page_range {
+        // Reserved pages are changed too, so their frames get the new access
+        let Some(entry) = (unsafe { leaf_entry(level_4_table, page.start_address(), false) })
+        else {
+            continue;
+        };
+        let flags = entry.flags();
+        let user_flags = PageTableFlags::USER_ACCESSIBLE | NO_ACCESS;
+        if !flags.intersects(user_flags) {
+            continue;
+        }
+        let shared =
+            flags.contains(PageTableFlags::PRESENT) && refs.contains_key(&entry.addr().as_u64());
+
+        let mut new_flags = flags - (user_flags | PageTableFlags::WRITABLE | COPY_ON_WRITE);
+        if !accessible {
+            new_flags |= NO_ACCESS;
+        } else {
+            new_flags |= PageTableFlags::USER_ACCESSIBLE;
+            if writable && shared {
+                new_flags |= COPY_ON_WRITE;
+            } else if writable {
+                new_flags |= PageTableFlags::WRITABLE;
+            }
+        }
+        entry.set_flags(new_flags);
+        x86_64::instructions::tlb::flush(page.start_address());
+    }
+}
+
+/// The number of physical frames in use, and the number there are
+pub fn frame_usage() -> (usize, usize) {
+    let memory_info = unsafe { MEMORY_INFO.as_ref().unwrap() };
+    memory_info.frame_allocator.usage()
+}
+
+/// The number of frames zeroed ahead of time and not handed out yet
+pub fn zeroed_frames() -> usize {
+    let memory_info = unsafe { MEMORY_INFO.as_ref().unwrap() };
+    memory_info.frame_allocator.zeroed_count()
+}
+
+/// Zeroes a free frame ahead of time, so that a later user mapping doesn't
+/// have to. Meant to be called while there is nothing else to do, returns
+/// false once there is nothing left worth zeroing.
+pub fn refill_zeroed_frame() -> bool {
+    // Interrupts may allocate frames too
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let memory_info = unsafe { MEMORY_INFO.as_mut().unwrap() };
+        memory_info.frame_allocator.refill_zeroed()
+    })
+}
+
 pub fn switch_to_pagetable(physaddr: PhysAddr) {
     let physaddr = physaddr.as_u64();
     unsafe {
@@ -221,7 +618,8 @@ pub fn switch_to_kernel_pagetable() {
     switch_to_pagetable(phys_addr);
 }
 
-/// Allocates pages in the level_4_table supplied
+/// Allocates pages in the level_4_table supplied. The frames are zeroed, so
+/// nothing previously stored in them can be read through the new pages.
 ///
 /// # Safety
 ///
@@ -250,7 +648,7 @@ pub unsafe fn allocate_pages(
     for page in page_range {
         let frame = memory_info
             .frame_allocator
-            .allocate_frame()
+            .allocate_zeroed_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
 
         mapper
@@ -286,20 +684,122 @@ pub unsafe fn deallocate_pages(
         Page::range_inclusive(start_page, end_page)
     };
 
-    // FIX ME - Create better frame allocator that allows deallocation in future
+    let mut refs = FRAME_REFS.lock();
     for page in page_range {
-        // let phys = mapper
-        //     .translate_page(page)
-        //     .map_err(|_| UnmapError::PageNotMapped)?;
-        mapper.unmap(page)?.1.flush();
+        // Reserved pages never touched and guard pages have no frame to free
+        if let Some(entry) = unsafe { leaf_entry(level_4_table, page.start_address(), false) } {
+            if !entry.is_unused() && !entry.flags().contains(PageTableFlags::PRESENT) {
+                entry.set_unused();
+                continue;
+            }
+        }
 
-        // memory_info.frame_allocator.deallocate_frame(phys);
+        // Device memory isn't ours to free, and shared frames are freed by
+        // whichever page table lets go of them last
+        let device = matches!(
+            mapper.translate(page.start_address()),
+            TranslateResult::Mapped { flags, .. } if flags.contains(DEVICE_MEMORY)
+        );
+        let (frame, flush) = mapper.unmap(page)?;
+        flush.flush();
+        if !device && unshare_frame(&mut refs, frame) {
+            unsafe { memory_info.frame_allocator.deallocate_frame(frame) };
+        }
     }
 
     Ok(())
 }
 
-pub fn map_physical_address_to_user(virtaddr: VirtAddr, physaddr: PhysAddr, size: usize) {
+/// Allocates a physically contiguous kernel stack of `size` bytes and returns its
+/// lowest address.
+///
+/// The stack is addressed through the physical memory mapping, which is part of
+/// every address space, so it remains usable while switching page tables.
+pub fn allocate_kernel_stack(size: usize) -> Option<VirtAddr> {
+    let memory_info = unsafe { MEMORY_INFO.as_mut().unwrap() };
+
+    let frame_count = size.div_ceil(4096);
+    let start_frame = memory_info
+        .frame_allocator
+        .allocate_contiguous(frame_count)?;
+
+    Some(memory_info.phys_mem_offset + start_frame.start_address().as_u64())
+}
+
+/// Frees a kernel stack allocated by `allocate_kernel_stack`
+///
+/// # Safety
+///
+/// `bottom` and `size` must be as the stack was allocated with, and nothing
+/// may run on it anymore.
+pub unsafe fn deallocate_kernel_stack(bottom: VirtAddr, size: usize) {
+    let memory_info = unsafe { MEMORY_INFO.as_mut().unwrap() };
+    let start_frame =
+        PhysFrame::containing_address(PhysAddr::new(bottom - memory_info.phys_mem_offset));
+    unsafe {
+        memory_info
+            .frame_allocator
+            .deallocate_contiguous(start_frame, size.div_ceil(4096))
+    };
+}
+
+/// Allocates `count` physically contiguous frames, e.g. for DMA, returning the first
+pub fn allocate_contiguous_frames(count: usize) -> Option<PhysFrame> {
+    let memory_info = unsafe { MEMORY_INFO.as_mut().unwrap() };
+    memory_info.frame_allocator.allocate_contiguous(count)
+}
+
+/// Gives back frames allocated by `allocate_contiguous_frames`
+///
+/// # Safety
+///
+/// `start` and `count` must be as they were allocated, and nothing may use
+/// the frames afterwards.
+pub unsafe fn deallocate_contiguous_frames(start: PhysFrame, count: usize) {
+    let memory_info = unsafe { MEMORY_INFO.as_mut().unwrap() };
+    unsafe {
+        memory_info
+            .frame_allocator
+            .deallocate_contiguous(start, count)
+    };
+}
+
+/// Maps fresh frames over `size` bytes from `start` in the kernel's page
+/// table, returning false if that isn't possible yet or memory ran out.
+/// Mappings in the upper half are shared by every page table.
+pub fn map_kernel_pages(start: VirtAddr, size: u64) -> bool {
+    let Some(memory_info) = (unsafe { MEMORY_INFO.as_mut() }) else {
+        return false;
+    };
+    let level_4_table: *mut PageTable = memory_info.kernel_l4_table;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for offset in (0..size).step_by(4096) {
+        if unsafe { allocate_pages(level_4_table, start + offset, 4096, flags) }.is_err() {
+            // Leave nothing behind, so that a later attempt can start afresh.
+            // This can run while FRAME_REFS is held, as growing the heap does,
+            // so unlike `deallocate_pages` it mustn't touch it. Kernel heap
+            // frames are never shared anyway.
+            let mut mapper =
+                unsafe { OffsetPageTable::new(&mut *level_4_table, memory_info.phys_mem_offset) };
+            for offset in (0..offset).step_by(4096) {
+                let page = Page::<Size4KiB>::containing_address(start + offset);
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                    unsafe { memory_info.frame_allocator.deallocate_frame(frame) };
+                }
+            }
+            return false;
+        }
+    }
+    true
+}
+
+pub fn map_physical_address_to_user(
+    virtaddr: VirtAddr,
+    physaddr: PhysAddr,
+    size: usize,
+    writable: bool,
+) {
     use x86_64::structures::paging::PageTableFlags as Flags;
 
     let memory_info = unsafe { MEMORY_INFO.as_mut().unwrap() };
@@ -321,7 +821,10 @@ pub fn map_physical_address_to_user(virtaddr: VirtAddr, physaddr: PhysAddr, size
         PhysFrame::range_inclusive(start_frame, end_frame)
     };
 
-    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+    let mut flags = Flags::PRESENT | Flags::USER_ACCESSIBLE | DEVICE_MEMORY;
+    if writable {
+        flags |= Flags::WRITABLE;
+    }
 
     for page in page_range {
         let map_to_result = unsafe {
@@ -343,53 +846,3 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
         None
     }
 }
-
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryRegions,
-    next: usize,
-}
-
-impl BootInfoFrameAllocator {
-    /// Create a FrameAllocator from the passed memory map.
-    ///
-    /// # Safety
-    ///
-    /// This function is unsafe because the caller must guarantee that the passed
-    /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryRegions) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
-        }
-    }
-
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.kind == MemoryRegionKind::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.start..r.end);
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
-}
-
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
-    }
-}
-
-// impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
-//     unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
-//         self.memory_map.
-//         todo!()
-//     }
-// }
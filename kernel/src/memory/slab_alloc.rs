@@ -1,4 +1,4 @@
-use crate::memory::allocator::Locked;
+use crate::memory::allocator::{self, Locked, HEAP_GROWTH};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{
     mem,
@@ -9,7 +9,11 @@ use core::{
 ///
 /// The sizes must each be power of 2 because they are also used as
 /// the block alignment (alignments must be always powers of 2).
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Number of entries in `SlabAllocator::stats`, one per block size and one
+/// for allocations bigger than any of them.
+pub const CLASSES: usize = BLOCK_SIZES.len() + 1;
 
 /// Choose an appropriate block size for the given layout.
 ///
@@ -23,9 +27,36 @@ struct ListNode {
     next: Option<&'static mut ListNode>,
 }
 
+/// Counters for the allocations of one size class
+#[derive(Clone, Copy, Default)]
+pub struct SizeClassStats {
+    pub allocations: usize,
+    pub frees: usize,
+    pub live_bytes: usize,
+    pub fallback_hits: usize, // allocations the fallback allocator had to serve
+}
+
+impl SizeClassStats {
+    const fn new() -> Self {
+        SizeClassStats {
+            allocations: 0,
+            frees: 0,
+            live_bytes: 0,
+            fallback_hits: 0,
+        }
+    }
+}
+
 pub struct SlabAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    stats: [SizeClassStats; CLASSES],
+}
+
+impl Default for SlabAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SlabAllocator {
@@ -35,6 +66,7 @@ impl SlabAllocator {
         SlabAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            stats: [SizeClassStats::new(); CLASSES],
         }
     }
 
@@ -49,8 +81,33 @@ impl SlabAllocator {
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
-    /// Allocates using the fallback allocator.
+    /// Bytes handed out by the fallback allocator, including free slab blocks
+    pub fn used(&self) -> usize {
+        self.fallback_allocator.used()
+    }
+
+    pub fn size(&self) -> usize {
+        self.fallback_allocator.size()
+    }
+
+    /// The counters for each size class, with allocations too big for any
+    /// class last
+    pub fn stats(&self) -> [SizeClassStats; CLASSES] {
+        self.stats
+    }
+
+    /// Allocates using the fallback allocator, growing the heap if it is full.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        // Enough for the allocation however it ends up aligned
+        let size = (layout.size() + layout.align()).next_multiple_of(HEAP_GROWTH);
+        if !allocator::grow_heap(self.fallback_allocator.top(), size) {
+            return ptr::null_mut();
+        }
+        unsafe { self.fallback_allocator.extend(size) };
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
             Err(_) => ptr::null_mut(),
@@ -62,30 +119,52 @@ unsafe impl GlobalAlloc for Locked<SlabAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         // println!("allocing: {layout:?}");
         let mut allocator = self.lock();
-        match list_index(&layout) {
+        let (class, size, from_fallback, ptr) = match list_index(&layout) {
             Some(index) => {
+                let block_size = BLOCK_SIZES[index];
                 match allocator.list_heads[index].take() {
                     Some(node) => {
                         allocator.list_heads[index] = node.next.take();
-                        node as *mut ListNode as *mut u8
+                        (index, block_size, false, node as *mut ListNode as *mut u8)
                     }
                     None => {
                         // no block exists in list => allocate new block
-                        let block_size = BLOCK_SIZES[index];
                         // only works if all block sizes are a power of 2
                         let block_align = block_size;
                         let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                        allocator.fallback_alloc(layout)
+                        (index, block_size, true, allocator.fallback_alloc(layout))
                     }
                 }
             }
-            None => allocator.fallback_alloc(layout),
+            None => (
+                CLASSES - 1,
+                layout.size(),
+                true,
+                allocator.fallback_alloc(layout),
+            ),
+        };
+
+        if !ptr.is_null() {
+            let stats = &mut allocator.stats[class];
+            stats.allocations += 1;
+            stats.live_bytes += size;
+            if from_fallback {
+                stats.fallback_hits += 1;
+            }
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         // println!("de-allocing: {layout:?}");
         let mut allocator = self.lock();
+        let (class, size) = match list_index(&layout) {
+            Some(index) => (index, BLOCK_SIZES[index]),
+            None => (CLASSES - 1, layout.size()),
+        };
+        allocator.stats[class].frees += 1;
+        allocator.stats[class].live_bytes -= size;
+
         match list_index(&layout) {
             Some(index) => {
                 let new_node = ListNode {
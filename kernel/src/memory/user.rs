@@ -0,0 +1,154 @@
+//! Access to userspace memory for syscalls. Every pointer a process passes to
+//! the kernel goes through here, so a bad one comes back as `BadAddress`
+//! instead of crashing or corrupting the kernel.
+
+use super::{active_level_4_table, user_page_flags, COPY_ON_WRITE, MEMORY_INFO};
+use crate::fs::errors::Error;
+use alloc::{string::String, vec::Vec};
+use core::{arch::global_asm, mem::MaybeUninit};
+use x86_64::{
+    structures::paging::{OffsetPageTable, Page, PageTableFlags, Size4KiB, Translate},
+    PhysAddr, VirtAddr,
+};
+
+/// End of the lower canonical half, above which nothing belongs to userspace
+pub const USER_END: usize = 0x0000_8000_0000_0000;
+
+/// Longest path accepted from userspace, including the terminating null
+pub const PATH_MAX: usize = 4096;
+
+// copy_user_raw(dst, src, len) copies `len` bytes from `src` to `dst` with
+// `rep movsb`, returning how many bytes were left uncopied. A fault during the
+// copy resumes at `user_copy_fixup` (see `fixup_fault`), where rcx still holds
+// that count.
+global_asm!(
+    ".global copy_user_raw",
+    "copy_user_raw:",
+    "mov rcx, rdx",
+    ".global user_copy_insn",
+    "user_copy_insn:",
+    "rep movsb",
+    ".global user_copy_fixup",
+    "user_copy_fixup:",
+    "mov rax, rcx",
+    "ret",
+);
+
+extern "C" {
+    fn copy_user_raw(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    static user_copy_insn: u8;
+    static user_copy_fixup: u8;
+}
+
+/// Called by the page fault handler for faults in ring 0. If the fault happened
+/// while copying to or from userspace, returns where to resume so the copy
+/// fails instead of the kernel.
+pub fn fixup_fault(instruction_pointer: VirtAddr) -> Option<VirtAddr> {
+    let (insn, fixup) = unsafe {
+        (
+            core::ptr::addr_of!(user_copy_insn) as u64,
+            core::ptr::addr_of!(user_copy_fixup) as u64,
+        )
+    };
+    (instruction_pointer.as_u64() == insn).then(|| VirtAddr::new(fixup))
+}
+
+/// Checks that `len` bytes at `addr` are mapped into the current process'
+/// address space and accessible from ring 3, and writable if `write` is set.
+/// Pages that are only reserved so far are allowed.
+pub fn check_range(addr: usize, len: usize, write: bool) -> Result<(), Error> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = addr.checked_add(len).ok_or(Error::BadAddress)?;
+    if end > USER_END {
+        return Err(Error::BadAddress);
+    }
+
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(addr as u64));
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end as u64 - 1));
+    for page in Page::range_inclusive(first, last) {
+        // Reserved pages count too, touching them gives them a frame
+        let flags = user_page_flags(page.start_address()).ok_or(Error::BadAddress)?;
+        if !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            return Err(Error::BadAddress);
+        }
+        // Copy-on-write pages are writable, the write just un-shares them first
+        if write && !flags.intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE) {
+            return Err(Error::BadAddress);
+        }
+    }
+    Ok(())
+}
+
+/// The physical address that the user address `addr` is currently backed by
+pub fn translate_user(addr: usize) -> Result<PhysAddr, Error> {
+    check_range(addr, 1, false)?;
+    // The page may only be reserved so far, nothing has touched it yet
+    super::handle_demand_fault(VirtAddr::new(addr as u64));
+
+    let memory_info = unsafe { MEMORY_INFO.as_ref().ok_or(Error::BadAddress)? };
+    let level_4_table = unsafe { active_level_4_table(memory_info.phys_mem_offset) }.0;
+    let mapper = unsafe { OffsetPageTable::new(level_4_table, memory_info.phys_mem_offset) };
+    mapper
+        .translate_addr(VirtAddr::new(addr as u64))
+        .ok_or(Error::BadAddress)
+}
+
+/// Copies `dst.len()` bytes from the user address `src` into `dst`
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), Error> {
+    check_range(src, dst.len(), false)?;
+    match unsafe { copy_user_raw(dst.as_mut_ptr(), src as *const u8, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(Error::BadAddress),
+    }
+}
+
+/// Copies `src` to the user address `dst`
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), Error> {
+    check_range(dst, src.len(), true)?;
+    match unsafe { copy_user_raw(dst as *mut u8, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(Error::BadAddress),
+    }
+}
+
+/// Reads a plain value, such as an integer or `#[repr(C)]` struct, from userspace
+pub fn read_from_user<T: Copy>(src: usize) -> Result<T, Error> {
+    let mut value = MaybeUninit::<T>::uninit();
+    let bytes = unsafe {
+        core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, core::mem::size_of::<T>())
+    };
+    copy_from_user(bytes, src)?;
+    Ok(unsafe { value.assume_init() })
+}
+
+/// Writes a plain value, such as an integer or `#[repr(C)]` struct, to userspace
+pub fn write_to_user<T: Copy>(dst: usize, value: &T) -> Result<(), Error> {
+    let bytes = unsafe {
+        core::slice::from_raw_parts(value as *const T as *const u8, core::mem::size_of::<T>())
+    };
+    copy_to_user(dst, bytes)
+}
+
+/// Reads a null-terminated UTF-8 string of at most `max_len` bytes, including
+/// the null, from userspace
+pub fn read_user_string(src: usize, max_len: usize) -> Result<String, Error> {
+    let mut bytes = Vec::new();
+    let mut addr = src;
+
+    // Copy a page at a time, as the string can end just before unmapped memory
+    while bytes.len() < max_len {
+        let page_left = 4096 - (addr % 4096);
+        let mut chunk = alloc::vec![0; page_left.min(max_len - bytes.len())];
+        copy_from_user(&mut chunk, addr)?;
+
+        if let Some(nul) = chunk.iter().position(|&b| b == 0) {
+            bytes.extend_from_slice(&chunk[..nul]);
+            return String::from_utf8(bytes).map_err(|_| Error::BadAddress);
+        }
+        bytes.extend_from_slice(&chunk);
+        addr += chunk.len();
+    }
+    Err(Error::NameTooLong)
+}
@@ -1,3 +1,4 @@
+use crate::fs::file::{O_RDONLY, O_WRONLY};
 use ps2_mouse::{Mouse, MouseState};
 use spin::Mutex;
 
@@ -5,14 +6,14 @@ pub static MOUSE: Mutex<Mouse> = Mutex::new(Mouse::new());
 
 // Initialize the mouse and set the on complete event.
 pub fn init_mouse() {
-    crate::fs::vfs::open("/dev/mouse").unwrap();
+    crate::fs::vfs::open("/dev/mouse", O_RDONLY).unwrap();
     MOUSE.lock().init().unwrap();
     MOUSE.lock().set_on_complete(on_complete);
 }
 
 // This will be fired when a packet is finished being processed.
 fn on_complete(mouse_state: MouseState) {
-    let file = crate::fs::vfs::open("/dev/mouse").unwrap();
+    let file = crate::fs::vfs::open("/dev/mouse", O_WRONLY).unwrap();
     let button_state =
         mouse_state.left_button_down() as u8 + ((mouse_state.right_button_down() as u8) << 1);
     let buf: [u8; 3] = [
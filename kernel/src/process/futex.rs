@@ -0,0 +1,63 @@
+//! Fast userspace mutexes. Userspace does its locking with atomics on a shared
+//! word and only calls into the kernel to sleep when it is contended, or to
+//! wake the threads sleeping on it.
+
+use crate::{fs::errors::Error, memory::user, process::wait_queue::WaitQueue};
+use alloc::{collections::BTreeMap, sync::Arc};
+use spin::Mutex;
+
+// `futex` operations. Every futex is treated as shared, so the private flag
+// makes no difference.
+pub const FUTEX_WAIT: usize = 0;
+pub const FUTEX_WAKE: usize = 1;
+pub const FUTEX_PRIVATE_FLAG: usize = 128;
+
+/// Threads sleeping on each futex, keyed by the physical address of its word so
+/// that processes sharing memory find the same queue
+static FUTEXES: Mutex<BTreeMap<u64, Arc<WaitQueue>>> = Mutex::new(BTreeMap::new());
+
+fn key(addr: usize) -> Result<u64, Error> {
+    if addr % core::mem::align_of::<u32>() != 0 {
+        return Err(Error::InvalidArgument);
+    }
+    Ok(user::translate_user(addr)?.as_u64())
+}
+
+/// Sleeps until woken by `wake` on the same word, unless it no longer holds
+/// `expected`, in which case this returns `WouldBlock` straight away. Wake ups
+/// can be spurious, so callers should re-check the word.
+pub fn wait(addr: usize, expected: u32) -> Result<usize, Error> {
+    let key = key(addr)?;
+    // Interrupts are off in syscalls, so no wake can slip in between the check
+    // and going to sleep
+    let value: u32 = user::read_from_user(addr)?;
+    if value != expected {
+        return Err(Error::WouldBlock);
+    }
+
+    let queue = FUTEXES.lock().entry(key).or_default().clone();
+    let result = queue.wait();
+
+    let mut futexes = FUTEXES.lock();
+    if queue.is_empty() {
+        futexes.remove(&key);
+    }
+    result.map(|_| 0)
+}
+
+/// Takes threads that are gone off every futex they were sleeping on, and
+/// forgets the futexes nobody is left sleeping on
+pub fn remove_threads(tids: &[usize]) {
+    FUTEXES.lock().retain(|_, queue| {
+        queue.remove_threads(tids);
+        !queue.is_empty()
+    });
+}
+
+/// Wakes up to `count` threads waiting on the word at `addr`, returning how
+/// many were woken
+pub fn wake(addr: usize, count: usize) -> Result<usize, Error> {
+    let key = key(addr)?;
+    let queue = FUTEXES.lock().get(&key).cloned();
+    Ok(queue.map_or(0, |queue| queue.wake(count)))
+}
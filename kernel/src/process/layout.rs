@@ -0,0 +1,59 @@
+//! Where things go in a process' address space. With ASLR, the executable,
+//! stack, heap and mmap area are each moved by a random number of pages for
+//! every program that is loaded, so their addresses can't be guessed. Booting
+//! with `norandmaps` turns it off, which is handy for debugging.
+
+use super::{vma::MMAP_BASE, vma::PAGE_SIZE, HEAP_START};
+use crate::{cmdline, random};
+
+/// Where executables are loaded without ASLR
+pub const LOAD_BASE: usize = 0x400000;
+/// The top of the main stack without ASLR
+pub const STACK_END: usize = 0x7000_0000_0000;
+
+// How far each area may be moved. The executable moves up by at most 256 MiB,
+// the mmap area and heap up by at most 1 TiB and the stack down by at most
+// 16 GiB, which keeps all of them clear of each other.
+const LOAD_RANDOM_RANGE: usize = 0x1000_0000;
+const MMAP_RANDOM_RANGE: usize = 0x100_0000_0000;
+const HEAP_RANDOM_RANGE: usize = 0x100_0000_0000;
+const STACK_RANDOM_RANGE: usize = 0x4_0000_0000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct AddressLayout {
+    pub load_base: usize,  // added to the executable's addresses
+    pub stack_end: usize,  // the top of the main stack
+    pub heap_start: usize, // where the program break starts
+    pub mmap_base: usize,  // where mmap starts looking for free space
+}
+
+impl Default for AddressLayout {
+    fn default() -> Self {
+        AddressLayout {
+            load_base: LOAD_BASE,
+            stack_end: STACK_END,
+            heap_start: HEAP_START,
+            mmap_base: MMAP_BASE,
+        }
+    }
+}
+
+impl AddressLayout {
+    /// A layout for a newly loaded program, randomised unless ASLR is off
+    pub fn new() -> Self {
+        if cmdline::has("norandmaps") {
+            return Self::default();
+        }
+        AddressLayout {
+            load_base: LOAD_BASE + random_offset(LOAD_RANDOM_RANGE),
+            stack_end: STACK_END - random_offset(STACK_RANDOM_RANGE),
+            heap_start: HEAP_START + random_offset(HEAP_RANDOM_RANGE),
+            mmap_base: MMAP_BASE + random_offset(MMAP_RANDOM_RANGE),
+        }
+    }
+}
+
+/// A random whole number of pages, less than `range` bytes
+fn random_offset(range: usize) -> usize {
+    random::below((range / PAGE_SIZE) as u64) as usize * PAGE_SIZE
+}
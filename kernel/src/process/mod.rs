@@ -1,26 +1,118 @@
 use crate::{
-    fs::{self, file::File},
-    scheduler,
+    fs::{
+        self,
+        file::{File, O_RDWR},
+    },
+    memory, scheduler,
 };
-use alloc::{collections::BTreeMap, format, sync::Arc};
+use alloc::{collections::BTreeMap, format, sync::Arc, vec, vec::Vec};
 use core::fmt::Display;
+use layout::AddressLayout;
+use signal::SignalState;
 use spin::Mutex;
+use vma::VmaList;
 use x86_64::{PhysAddr, VirtAddr};
 
+pub mod futex;
+pub mod layout;
+pub mod signal;
+pub mod vma;
+pub mod wait_queue;
+
 #[derive(Clone, Debug, PartialEq)]
-pub enum ProcessState {
-    // a task's state can either be
+pub enum ThreadState {
+    // a thread's state can either be
     SavedContext(Context),            // a saved context
     StartingInfo(VirtAddr, VirtAddr), // or a starting instruction and stack pointer
-    Exiting(),
+    Blocked(Context),                 // or asleep on a wait queue with a saved context
+    Exited(usize),                    // or finished, keeping its exit value until joined
+}
+
+impl ThreadState {
+    pub fn is_runnable(&self) -> bool {
+        matches!(
+            self,
+            ThreadState::SavedContext(_) | ThreadState::StartingInfo(..)
+        )
+    }
+}
+
+const KERNEL_STACK_SIZE: usize = 4096 * 8;
+
+/// Where the program break starts without ASLR, i.e. the bottom of the heap
+/// `brk` grows
+pub const HEAP_START: usize = 0x5000_0000_0000;
+/// How far past the start of the heap the program break may be moved
+pub const HEAP_MAX_SIZE: usize = 0x100_0000_0000;
+
+/// File descriptors run from 0 up to, but not including, this limit
+pub const MAX_FILE_DESCRIPTORS: u32 = 256;
+
+/// The stack used while a thread is executing in ring 0, i.e. during syscalls
+/// and interrupts taken from user mode. Each thread owns one, so a context
+/// saved part-way through a syscall is never overwritten by another thread.
+pub struct KernelStack {
+    bottom: VirtAddr,
+}
+
+impl Default for KernelStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KernelStack {
+    pub fn new() -> KernelStack {
+        let bottom = memory::allocate_kernel_stack(KERNEL_STACK_SIZE)
+            .expect("Could not allocate kernel stack");
+        KernelStack { bottom }
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.bottom + KERNEL_STACK_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        // Threads are only dropped once they have been switched away from for good
+        unsafe { memory::deallocate_kernel_stack(self.bottom, KERNEL_STACK_SIZE) };
+    }
+}
+
+/// A flow of execution within a process. Threads are what gets scheduled,
+/// while everything they share lives in their `Process`.
+pub struct Thread {
+    pub thread_id: usize,                      // unique across all processes
+    pub state: ThreadState,                    // the current state of the thread
+    pub kernel_stack: KernelStack,             // stack for syscalls and interrupts
+    pub user_stack: Option<(VirtAddr, usize)>, // stack allocated for the thread, if any
+    pub wait_token: u64,                       // the wait it last blocked in
+}
+
+impl Thread {
+    pub fn new(thread_id: usize, state: ThreadState) -> Thread {
+        Thread {
+            thread_id,
+            state,
+            kernel_stack: KernelStack::new(),
+            user_stack: None,
+            wait_token: 0,
+        }
+    }
 }
 
 pub struct Process {
     pub process_id: usize,
-    pub state: ProcessState,       // the current state of the process
+    pub parent_id: usize,          // the process to notify on exit, 0 if none
+    pub threads: Vec<Thread>,      // the first is the main thread, sharing the PID
+    pub exit_status: Option<i32>,  // the wait status once exited, until collected
     pub page_table_phys: PhysAddr, // the page table for this process
     pub file_descriptors: BTreeMap<u32, Arc<Mutex<File>>>, // file descriptors for Stdio
-    pub mmap_next_addr: usize,     // next virtual address to use for mmap
+    pub vmas: VmaList,             // the regions mapped in the address space
+    pub layout: AddressLayout,     // where the program, stack, heap and mmaps were put
+    pub brk: usize,                // the end of the heap, which starts at layout.heap_start
+    pub signals: SignalState,      // pending signals and their handlers
 }
 
 impl Process {
@@ -28,40 +120,71 @@ impl Process {
         exec_base: VirtAddr,
         stack_end: VirtAddr,
         page_table_phys: PhysAddr,
-        parent_id: usize,
+        process_id: usize,
     ) -> Process {
-        let id = if parent_id == 0 {
+        let id = if process_id == 0 {
             scheduler::SCHEDULER.read().get_available_pid()
         } else {
-            parent_id
+            process_id
         };
 
         let mut file_descriptors = BTreeMap::new();
-        file_descriptors.insert(0, fs::vfs::open(&format!("/stdio/{id}/stdin")).unwrap());
-        file_descriptors.insert(1, fs::vfs::open(&format!("/stdio/{id}/stdout")).unwrap());
+        // stdin is also written through by the kernel when keys are pressed
+        let stdin = fs::vfs::open(&format!("/stdio/{id}/stdin"), O_RDWR).unwrap();
+        let stdout = fs::vfs::open(&format!("/stdio/{id}/stdout"), O_RDWR).unwrap();
+        file_descriptors.insert(0, stdin);
+        file_descriptors.insert(1, stdout);
 
         Process {
             process_id: id,
-            state: ProcessState::StartingInfo(exec_base, stack_end),
+            parent_id: 0,
+            threads: vec![Thread::new(
+                id,
+                ThreadState::StartingInfo(exec_base, stack_end),
+            )],
+            exit_status: None,
             page_table_phys,
             file_descriptors,
-            mmap_next_addr: 0x4000_0000_0000,
+            vmas: VmaList::default(),
+            layout: AddressLayout::default(),
+            brk: HEAP_START,
+            signals: SignalState::default(),
         }
     }
+
+    pub fn is_zombie(&self) -> bool {
+        self.exit_status.is_some()
+    }
+
+    /// The process and thread IDs this process holds
+    pub fn ids(&self) -> impl Iterator<Item = usize> + '_ {
+        core::iter::once(self.process_id).chain(self.threads.iter().map(|t| t.thread_id))
+    }
+
+    /// The lowest file descriptor not currently in use, as POSIX requires new
+    /// descriptors to be allocated
+    pub fn lowest_free_fd(&self) -> Option<u32> {
+        (0..MAX_FILE_DESCRIPTORS).find(|fd| !self.file_descriptors.contains_key(fd))
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        memory::free_user_pagetable(self.page_table_phys);
+    }
 }
 
 impl Display for Process {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(
-            f,
-            "PT: {}, Context: {:#x?}",
-            self.page_table_phys.as_u64(),
-            self.state
-        )
+        write!(f, "PT: {}", self.page_table_phys.as_u64())?;
+        for thread in &self.threads {
+            write!(f, ", Thread #{}: {:#x?}", thread.thread_id, thread.state)?;
+        }
+        Ok(())
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 #[repr(C, packed)]
 pub struct Context {
     pub r15: usize,
@@ -85,3 +208,39 @@ pub struct Context {
     pub rsp: usize,
     pub ss: usize,
 }
+
+/// A register dump, for crash reports
+impl Display for Context {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // Copy the registers out, as fields of a packed struct can't be borrowed
+        let Context {
+            r15,
+            r14,
+            r13,
+            r12,
+            r11,
+            r10,
+            r9,
+            r8,
+            rbp,
+            rsi,
+            rdi,
+            rdx,
+            rcx,
+            rbx,
+            rax,
+            rip,
+            cs,
+            rflags,
+            rsp,
+            ss,
+        } = *self;
+        writeln!(f, "RIP: {rip:#018x} RSP: {rsp:#018x} RFLAGS: {rflags:#x}")?;
+        writeln!(f, "RAX: {rax:#018x} RBX: {rbx:#018x} RCX: {rcx:#018x}")?;
+        writeln!(f, "RDX: {rdx:#018x} RSI: {rsi:#018x} RDI: {rdi:#018x}")?;
+        writeln!(f, "RBP: {rbp:#018x} R8:  {r8:#018x} R9:  {r9:#018x}")?;
+        writeln!(f, "R10: {r10:#018x} R11: {r11:#018x} R12: {r12:#018x}")?;
+        writeln!(f, "R13: {r13:#018x} R14: {r14:#018x} R15: {r15:#018x}")?;
+        write!(f, "CS: {cs:#x} SS: {ss:#x}")
+    }
+}
@@ -0,0 +1,268 @@
+use crate::{
+    fs::errors::Error,
+    gdt,
+    memory::user::{self, USER_END},
+    process::Context,
+};
+use core::mem::size_of;
+use x86_64::registers::rflags::RFlags;
+
+// Signal numbers, matching Linux on x86_64
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGABRT: usize = 6;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGURG: usize = 23;
+pub const SIGWINCH: usize = 28;
+
+/// Signals are numbered from 1 up to, but not including, this
+pub const NSIG: usize = 65;
+
+// Special handler values
+pub const SIG_DFL: usize = 0;
+pub const SIG_IGN: usize = 1;
+
+// `SigAction` flags
+pub const SA_RESTORER: usize = 0x0400_0000;
+pub const SA_NODEFER: usize = 0x4000_0000;
+pub const SA_RESETHAND: usize = 0x8000_0000;
+
+// `rt_sigprocmask` operations
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+/// Bytes below the stack pointer that user code may use without moving it,
+/// which a signal frame must not overwrite
+const RED_ZONE: usize = 128;
+
+/// Signals that can be neither caught, blocked nor ignored
+const UNBLOCKABLE: u64 = sig_bit(SIGKILL) | sig_bit(SIGSTOP);
+
+/// The bit representing a signal in a signal mask
+pub const fn sig_bit(signal: usize) -> u64 {
+    1 << (signal - 1)
+}
+
+/// How a process handles a signal, laid out like the kernel `struct sigaction`
+/// that `rt_sigaction` takes
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SigAction {
+    pub handler: usize,
+    pub flags: usize,
+    /// Where the handler returns to, which must call `rt_sigreturn`
+    pub restorer: usize,
+    /// Signals blocked while the handler runs
+    pub mask: u64,
+}
+
+/// What the kernel does with a signal the process has not set a handler for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DefaultAction {
+    Terminate,
+    Ignore,
+}
+
+pub fn default_action(signal: usize) -> DefaultAction {
+    match signal {
+        // Job control isn't supported, so stop and continue signals do nothing
+        SIGCHLD | SIGCONT | SIGURG | SIGWINCH | SIGSTOP | SIGTSTP => DefaultAction::Ignore,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+/// Per-process signal state
+#[derive(Debug, Clone)]
+pub struct SignalState {
+    pub pending: u64,
+    pub blocked: u64,
+    actions: [SigAction; NSIG - 1],
+}
+
+impl Default for SignalState {
+    fn default() -> Self {
+        SignalState {
+            pending: 0,
+            blocked: 0,
+            actions: [SigAction::default(); NSIG - 1],
+        }
+    }
+}
+
+impl SignalState {
+    pub fn action(&self, signal: usize) -> SigAction {
+        self.actions[signal - 1]
+    }
+
+    pub fn set_action(&mut self, signal: usize, action: SigAction) {
+        self.actions[signal - 1] = action;
+    }
+
+    pub fn set_blocked(&mut self, mask: u64) {
+        self.blocked = mask & !UNBLOCKABLE;
+    }
+
+    /// Whether a signal would currently be thrown away rather than delivered
+    pub fn is_ignored(&self, signal: usize) -> bool {
+        match self.action(signal).handler {
+            SIG_IGN => true,
+            SIG_DFL => default_action(signal) == DefaultAction::Ignore,
+            _ => false,
+        }
+    }
+
+    /// Pending signals that are not blocked
+    pub fn deliverable(&self) -> u64 {
+        self.pending & !self.blocked
+    }
+
+    /// Removes and returns the lowest numbered deliverable signal
+    pub fn take_next(&mut self) -> Option<usize> {
+        let deliverable = self.deliverable();
+        if deliverable == 0 {
+            return None;
+        }
+        let signal = deliverable.trailing_zeros() as usize + 1;
+        self.pending &= !sig_bit(signal);
+        Some(signal)
+    }
+
+    /// The state a forked child starts with: the same handlers and mask, but
+    /// nothing pending
+    pub fn forked(&self) -> SignalState {
+        SignalState {
+            pending: 0,
+            ..self.clone()
+        }
+    }
+
+    /// Handlers point into the old program, so exec resets them. Ignored
+    /// signals stay ignored.
+    pub fn reset_handlers(&mut self) {
+        for action in self.actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SigAction::default();
+            }
+        }
+    }
+}
+
+/// What is pushed to the user stack when a handler is run. The handler returns
+/// into `restorer`, whose `rt_sigreturn` finds the rest just above the stack
+/// pointer.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct SignalFrame {
+    pub restorer: usize,
+    pub signal: usize,
+    /// The interrupted user mode context
+    pub context: Context,
+    /// The signal mask to restore
+    pub blocked: u64,
+}
+
+/// What happened when delivering a process' pending signals
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignalDelivery {
+    /// Nothing to deliver, the context is unchanged
+    None,
+    /// The context was rewritten to run a handler
+    Handler,
+    /// The process was terminated
+    Terminated,
+}
+
+/// Rewrites a user mode `context` to run `action`'s handler for `signal`,
+/// saving the interrupted context and signal mask in a frame on the user stack.
+/// This function assumes the process' page table is active.
+pub fn push_frame(
+    context: &mut Context,
+    signal: usize,
+    action: &SigAction,
+    blocked: u64,
+) -> Result<(), Error> {
+    // `sigaction` checks these too, but returning to a non-canonical address
+    // would fault in ring 0, so make sure
+    if action.handler >= USER_END || action.restorer >= USER_END {
+        return Err(Error::BadAddress);
+    }
+
+    // Contexts saved on syscall entry don't have their segments filled in
+    let (code_selector, data_selector) = gdt::get_usermode_segments();
+    context.cs = code_selector.0 as usize;
+    context.ss = data_selector.0 as usize;
+
+    let frame = SignalFrame {
+        restorer: action.restorer,
+        signal,
+        context: *context,
+        blocked,
+    };
+    // Leave the frame's restorer where a call would have put the return address
+    let frame_addr = context
+        .rsp
+        .checked_sub(RED_ZONE + size_of::<SignalFrame>())
+        .map(|addr| (addr & !0xf) - 8)
+        .ok_or(Error::BadAddress)?;
+    user::write_to_user(frame_addr, &frame)?;
+
+    context.rip = action.handler;
+    context.rsp = frame_addr;
+    context.rdi = signal;
+    context.rsi = 0;
+    context.rdx = 0;
+    // Handlers expect the direction flag clear, and shouldn't be single stepped
+    context.rflags &= !(RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG).bits() as usize;
+    Ok(())
+}
+
+/// Restores the context saved by `push_frame` once a handler has returned
+/// through `rt_sigreturn`, returning the signal mask to go back to. The frame
+/// is in user memory, so only the parts of it user code could set itself are
+/// trusted.
+pub fn restore_frame(context: &mut Context) -> Result<u64, Error> {
+    // The handler's `ret` popped the restorer
+    let frame_addr = context
+        .rsp
+        .checked_sub(size_of::<usize>())
+        .ok_or(Error::BadAddress)?;
+    let frame: SignalFrame = user::read_from_user(frame_addr)?;
+
+    let mut restored = frame.context;
+    if restored.rip >= USER_END || restored.rsp >= USER_END {
+        return Err(Error::BadAddress);
+    }
+    let (code_selector, data_selector) = gdt::get_usermode_segments();
+    restored.cs = code_selector.0 as usize;
+    restored.ss = data_selector.0 as usize;
+    let user_flags = RFlags::CARRY_FLAG
+        | RFlags::PARITY_FLAG
+        | RFlags::AUXILIARY_CARRY_FLAG
+        | RFlags::ZERO_FLAG
+        | RFlags::SIGN_FLAG
+        | RFlags::TRAP_FLAG
+        | RFlags::DIRECTION_FLAG
+        | RFlags::OVERFLOW_FLAG
+        | RFlags::ALIGNMENT_CHECK;
+    restored.rflags = (restored.rflags & user_flags.bits() as usize)
+        | (RFlags::INTERRUPT_FLAG | RFlags::from_bits_truncate(0x2)).bits() as usize;
+
+    *context = restored;
+    Ok(frame.blocked)
+}
@@ -0,0 +1,356 @@
+//! Tracking of what is mapped where in a process' address space. Each region
+//! (VMA) covers whole pages, and a process' regions never overlap.
+
+use crate::fs::{errors::Error, vnode::VNode};
+use crate::memory::user::USER_END;
+use alloc::{sync::Arc, vec::Vec};
+
+pub const PAGE_SIZE: usize = 0x1000;
+
+/// Where `mmap` starts looking for free space when it isn't given an address,
+/// without ASLR
+pub const MMAP_BASE: usize = 0x4000_0000_0000;
+
+// Protection bits, as `mmap` and `mprotect` take them
+pub const PROT_NONE: usize = 0x0;
+pub const PROT_READ: usize = 0x1;
+pub const PROT_WRITE: usize = 0x2;
+pub const PROT_EXEC: usize = 0x4;
+
+/// What a region's contents come from
+#[derive(Clone)]
+pub enum Backing {
+    /// Memory belonging to the process alone
+    Anonymous,
+    /// Part of a file or device, starting `offset` bytes in
+    File {
+        vnode: Arc<dyn VNode>,
+        offset: usize,
+    },
+}
+
+#[derive(Clone)]
+pub struct Vma {
+    pub start: usize,
+    pub len: usize,
+    pub prot: usize,
+    pub backing: Backing,
+    pub name: Option<&'static str>, // e.g. "[heap]", shown in /proc/<pid>/maps
+}
+
+impl Vma {
+    pub fn anonymous(start: usize, len: usize, prot: usize, name: Option<&'static str>) -> Vma {
+        Vma {
+            start,
+            len,
+            prot,
+            backing: Backing::Anonymous,
+            name,
+        }
+    }
+
+    pub fn end(&self) -> usize {
+        self.start + self.len
+    }
+
+    /// Whether `next` starts right where this ends and could be part of the
+    /// same region
+    fn continues_into(&self, next: &Vma) -> bool {
+        let backing_continues = match (&self.backing, &next.backing) {
+            (Backing::Anonymous, Backing::Anonymous) => true,
+            (
+                Backing::File { vnode, offset },
+                Backing::File {
+                    vnode: next_vnode,
+                    offset: next_offset,
+                },
+            ) => Arc::ptr_eq(vnode, next_vnode) && offset + self.len == *next_offset,
+            _ => false,
+        };
+        self.end() == next.start
+            && self.prot == next.prot
+            && self.name == next.name
+            && backing_continues
+    }
+
+    /// The part of this region between `start` and `end`, which must lie within it
+    fn slice(&self, start: usize, end: usize) -> Vma {
+        let backing = match &self.backing {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::File { vnode, offset } => Backing::File {
+                vnode: vnode.clone(),
+                offset: offset + (start - self.start),
+            },
+        };
+        Vma {
+            start,
+            len: end - start,
+            prot: self.prot,
+            backing,
+            name: self.name,
+        }
+    }
+}
+
+/// A process' regions, kept sorted by address
+#[derive(Clone, Default)]
+pub struct VmaList {
+    vmas: Vec<Vma>,
+}
+
+impl VmaList {
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas.iter()
+    }
+
+    /// The region containing `addr`, if any
+    pub fn find(&self, addr: usize) -> Option<&Vma> {
+        self.vmas.iter().find(|v| v.start <= addr && addr < v.end())
+    }
+
+    /// Whether nothing is mapped anywhere in `len` bytes from `start`
+    pub fn is_free(&self, start: usize, len: usize) -> bool {
+        let Some(end) = start.checked_add(len) else {
+            return false;
+        };
+        end <= USER_END && !self.vmas.iter().any(|v| v.start < end && start < v.end())
+    }
+
+    /// Picks where to put a new mapping of `len` bytes, using `hint` if that
+    /// space is free and otherwise the lowest free space from `base` up to
+    /// `limit`
+    pub fn find_free(&self, hint: usize, len: usize, base: usize, limit: usize) -> Option<usize> {
+        if hint != 0 && hint % PAGE_SIZE == 0 && self.is_free(hint, len) {
+            return Some(hint);
+        }
+
+        let mut candidate = base;
+        for vma in self.vmas.iter().filter(|v| v.end() > base) {
+            if vma.start >= candidate.checked_add(len)? {
+                break;
+            }
+            candidate = candidate.max(vma.end());
+        }
+        (candidate.checked_add(len)? <= limit).then_some(candidate)
+    }
+
+    /// Adds a region, which must not overlap any others. It is merged with
+    /// its neighbours if they carry on where it leaves off.
+    pub fn insert(&mut self, vma: Vma) {
+        debug_assert!(self.is_free(vma.start, vma.len));
+        let idx = self.vmas.partition_point(|v| v.start < vma.start);
+        self.vmas.insert(idx, vma);
+
+        if idx + 1 < self.vmas.len() && self.vmas[idx].continues_into(&self.vmas[idx + 1]) {
+            let next = self.vmas.remove(idx + 1);
+            self.vmas[idx].len += next.len;
+        }
+        if idx > 0 && self.vmas[idx - 1].continues_into(&self.vmas[idx]) {
+            let this = self.vmas.remove(idx);
+            self.vmas[idx - 1].len += this.len;
+        }
+    }
+
+    /// Takes `len` bytes from `start` out of the list, splitting regions that
+    /// are only partly covered. Returns the pieces removed, so their pages can
+    /// be unmapped.
+    pub fn remove(&mut self, start: usize, len: usize) -> Vec<Vma> {
+        let end = start.saturating_add(len);
+        let mut removed = Vec::new();
+        let mut kept = Vec::with_capacity(self.vmas.len());
+        for vma in self.vmas.drain(..) {
+            if vma.end() <= start || end <= vma.start {
+                kept.push(vma);
+                continue;
+            }
+            if vma.start < start {
+                kept.push(vma.slice(vma.start, start));
+            }
+            removed.push(vma.slice(vma.start.max(start), vma.end().min(end)));
+            if end < vma.end() {
+                kept.push(vma.slice(end, vma.end()));
+            }
+        }
+        self.vmas = kept;
+        removed
+    }
+
+    /// Changes the protection of `len` bytes from `start`, all of which must
+    /// be mapped
+    pub fn protect(&mut self, start: usize, len: usize, prot: usize) -> Result<(), Error> {
+        // Check for holes before changing anything, like Linux's ENOMEM
+        let end = start.checked_add(len).ok_or(Error::OutOfMemory)?;
+        let mut covered = start;
+        for vma in self
+            .vmas
+            .iter()
+            .filter(|v| v.end() > start && v.start < end)
+        {
+            if vma.start > covered {
+                break;
+            }
+            covered = vma.end();
+        }
+        if covered < end {
+            return Err(Error::OutOfMemory);
+        }
+
+        for vma in self.remove(start, len) {
+            self.insert(Vma { prot, ..vma });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RW: usize = PROT_READ | PROT_WRITE;
+
+    fn list(vmas: &[(usize, usize, usize)]) -> VmaList {
+        let mut list = VmaList::default();
+        for &(start, len, prot) in vmas {
+            list.insert(Vma::anonymous(start, len, prot, None));
+        }
+        list
+    }
+
+    fn ranges(list: &VmaList) -> Vec<(usize, usize, usize)> {
+        list.iter().map(|v| (v.start, v.len, v.prot)).collect()
+    }
+
+    #[test]
+    fn insert_merges_adjacent_regions() {
+        let list = list(&[
+            (0x3000, 0x1000, RW),
+            (0x1000, 0x1000, RW),
+            (0x2000, 0x1000, RW),
+        ]);
+        assert_eq!(ranges(&list), [(0x1000, 0x3000, RW)]);
+    }
+
+    #[test]
+    fn insert_keeps_regions_that_differ_apart() {
+        let mut list = list(&[(0x1000, 0x1000, RW), (0x2000, 0x1000, PROT_READ)]);
+        list.insert(Vma::anonymous(0x3000, 0x1000, PROT_READ, Some("[stack]")));
+        assert_eq!(
+            ranges(&list),
+            [
+                (0x1000, 0x1000, RW),
+                (0x2000, 0x1000, PROT_READ),
+                (0x3000, 0x1000, PROT_READ)
+            ]
+        );
+    }
+
+    #[test]
+    fn find_and_is_free() {
+        let list = list(&[(0x2000, 0x2000, RW)]);
+        assert!(list.find(0x1fff).is_none());
+        assert_eq!(list.find(0x3fff).map(|v| v.start), Some(0x2000));
+        assert!(list.find(0x4000).is_none());
+        assert!(list.is_free(0x1000, 0x1000));
+        assert!(!list.is_free(0x1000, 0x1001));
+        assert!(list.is_free(0x4000, 0x1000));
+        assert!(!list.is_free(USER_END - 0x1000, 0x2000));
+        assert!(!list.is_free(usize::MAX - 0xfff, 0x1000));
+    }
+
+    #[test]
+    fn find_free_takes_a_free_hint() {
+        let list = list(&[(0x5000, 0x1000, RW)]);
+        assert_eq!(
+            list.find_free(0x4000, 0x1000, 0x10000, 0x20000),
+            Some(0x4000)
+        );
+    }
+
+    #[test]
+    fn find_free_ignores_bad_hints() {
+        let list = list(&[(0x10000, 0x1000, RW)]);
+        // Overlapping, and not page aligned
+        assert_eq!(
+            list.find_free(0xf000, 0x2000, 0x10000, 0x20000),
+            Some(0x11000)
+        );
+        assert_eq!(
+            list.find_free(0x12001, 0x1000, 0x10000, 0x20000),
+            Some(0x11000)
+        );
+    }
+
+    #[test]
+    fn find_free_fills_the_first_gap_big_enough() {
+        let list = list(&[
+            (0x10000, 0x1000, RW),
+            (0x12000, 0x1000, PROT_READ),
+            (0x16000, 0x1000, RW),
+        ]);
+        assert_eq!(list.find_free(0, 0x1000, 0x10000, 0x20000), Some(0x11000));
+        assert_eq!(list.find_free(0, 0x2000, 0x10000, 0x20000), Some(0x13000));
+        assert_eq!(list.find_free(0, 0x4000, 0x10000, 0x20000), Some(0x17000));
+    }
+
+    #[test]
+    fn find_free_starts_at_base_and_stops_at_limit() {
+        let list = list(&[(0x1000, 0x1000, RW)]);
+        assert_eq!(list.find_free(0, 0x1000, 0x8000, 0x10000), Some(0x8000));
+        assert_eq!(list.find_free(0, 0x8000, 0x8000, 0x10000), Some(0x8000));
+        assert_eq!(list.find_free(0, 0x9000, 0x8000, 0x10000), None);
+    }
+
+    #[test]
+    fn remove_splits_partly_covered_regions() {
+        let mut list = list(&[(0x1000, 0x4000, RW), (0x6000, 0x2000, PROT_READ)]);
+        let removed = list.remove(0x2000, 0x5000);
+        assert_eq!(
+            removed
+                .iter()
+                .map(|v| (v.start, v.len, v.prot))
+                .collect::<Vec<_>>(),
+            [(0x2000, 0x3000, RW), (0x6000, 0x1000, PROT_READ)]
+        );
+        assert_eq!(
+            ranges(&list),
+            [(0x1000, 0x1000, RW), (0x7000, 0x1000, PROT_READ)]
+        );
+    }
+
+    #[test]
+    fn remove_of_nothing_mapped_changes_nothing() {
+        let mut list = list(&[(0x1000, 0x1000, RW)]);
+        assert!(list.remove(0x3000, 0x1000).is_empty());
+        assert_eq!(ranges(&list), [(0x1000, 0x1000, RW)]);
+    }
+
+    #[test]
+    fn protect_splits_and_merges() {
+        let mut list = list(&[(0x1000, 0x3000, RW)]);
+        list.protect(0x2000, 0x1000, PROT_READ).unwrap();
+        assert_eq!(
+            ranges(&list),
+            [
+                (0x1000, 0x1000, RW),
+                (0x2000, 0x1000, PROT_READ),
+                (0x3000, 0x1000, RW)
+            ]
+        );
+        list.protect(0x2000, 0x1000, RW).unwrap();
+        assert_eq!(ranges(&list), [(0x1000, 0x3000, RW)]);
+    }
+
+    #[test]
+    fn protect_rejects_holes_without_changing_anything() {
+        let mut list = list(&[(0x1000, 0x1000, RW), (0x3000, 0x1000, RW)]);
+        assert_eq!(
+            list.protect(0x1000, 0x3000, PROT_READ),
+            Err(Error::OutOfMemory)
+        );
+        assert_eq!(
+            list.protect(0x0, 0x2000, PROT_READ),
+            Err(Error::OutOfMemory)
+        );
+        assert_eq!(ranges(&list), [(0x1000, 0x1000, RW), (0x3000, 0x1000, RW)]);
+    }
+}
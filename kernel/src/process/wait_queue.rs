@@ -0,0 +1,87 @@
+use crate::{fs::errors::Error, scheduler};
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+/// Tells each wait apart, so that a waiter left behind by a thread that died
+/// can't wake whichever thread gets its ID next
+static NEXT_TOKEN: AtomicU64 = AtomicU64::new(1);
+
+/// A list of threads sleeping until some event happens, e.g. data arriving
+/// on a queue-backed vnode.
+#[derive(Debug, Default)]
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<(usize, u64)>>, // thread ID and wait token
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Puts the current thread to sleep until `wake_all` is called on this queue.
+    ///
+    /// This switches to another thread before returning, so it must only be
+    /// called from a syscall with no locks held. Wake ups can be spurious, so
+    /// callers should re-check whatever they were waiting for. Returns
+    /// `Interrupted` instead of sleeping, or once woken, if a signal is pending.
+    pub fn wait(&self) -> Result<(), Error> {
+        if scheduler::SCHEDULER.read().has_pending_signals() {
+            return Err(Error::Interrupted);
+        }
+
+        let tid = scheduler::SCHEDULER.read().get_cur_tid();
+        let token = NEXT_TOKEN.fetch_add(1, Ordering::Relaxed);
+        self.waiters.lock().push_back((tid, token));
+
+        // Blocked threads aren't picked, so this returns once we're woken
+        scheduler::SCHEDULER.read().block_current(token);
+        scheduler::yield_now();
+
+        // Something other than this queue, e.g. a signal, may have woken us
+        self.remove_threads(&[tid]);
+
+        if scheduler::SCHEDULER.read().has_pending_signals() {
+            return Err(Error::Interrupted);
+        }
+        Ok(())
+    }
+
+    /// Wakes every thread sleeping on this queue
+    pub fn wake_all(&self) {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        let scheduler = scheduler::SCHEDULER.read();
+        for (tid, token) in waiters {
+            scheduler.wake_waiter(tid, token);
+        }
+    }
+
+    /// Wakes up to `count` threads sleeping on this queue, longest waiting
+    /// first, returning how many were woken
+    pub fn wake(&self, count: usize) -> usize {
+        let mut woken = 0;
+        while woken < count {
+            let Some((tid, token)) = self.waiters.lock().pop_front() else {
+                break;
+            };
+            // Threads already woken some other way, or gone, don't count
+            if scheduler::SCHEDULER.read().wake_waiter(tid, token) {
+                woken += 1;
+            }
+        }
+        woken
+    }
+
+    /// Takes the threads in `tids` off this queue, e.g. because they exited
+    pub fn remove_threads(&self, tids: &[usize]) {
+        self.waiters
+            .lock()
+            .retain(|(waiter, _)| !tids.contains(waiter));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+}
@@ -0,0 +1,60 @@
+//! The kernel's random number generator. It is seeded once at boot from the
+//! CPU's hardware entropy source, and is not meant for cryptographic use.
+
+use core::arch::{asm, x86_64::__cpuid_count};
+use spin::Mutex;
+use x86_64::instructions::random::RdRand;
+
+/// The SplitMix64 state, advanced by a fixed amount for every number drawn
+static STATE: Mutex<u64> = Mutex::new(0);
+
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Seeds the generator from RDSEED, falling back on RDRAND and then on the
+/// time stamp counter for CPUs with neither
+pub fn init() {
+    let seed = rdseed()
+        .or_else(|| RdRand::new().and_then(|rdrand| rdrand.get_u64()))
+        .unwrap_or_else(|| {
+            println!("No hardware random number generator, seeding from the TSC");
+            unsafe { core::arch::x86_64::_rdtsc() }
+        });
+    *STATE.lock() = seed;
+}
+
+/// Reads a seed straight from the CPU's entropy source, if it has one
+fn rdseed() -> Option<u64> {
+    // CPUID leaf 7 reports RDSEED in bit 18 of EBX
+    let max_leaf = unsafe { __cpuid_count(0, 0) }.eax;
+    if max_leaf < 7 || unsafe { __cpuid_count(7, 0) }.ebx & (1 << 18) == 0 {
+        return None;
+    }
+    // It can run dry for a moment, so give it a few tries as Intel suggests
+    for _ in 0..10 {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdseed {value}", "setc {ok}", value = out(reg) value, ok = out(reg_byte) ok,
+                 options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// A random 64-bit number
+pub fn next_u64() -> u64 {
+    let mut state = STATE.lock();
+    *state = state.wrapping_add(GOLDEN_GAMMA);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// A random number below `bound`, which must not be 0
+pub fn below(bound: u64) -> u64 {
+    next_u64() % bound
+}
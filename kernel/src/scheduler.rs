@@ -1,10 +1,19 @@
 use crate::{
     elf,
     fs::{self, file::File},
-    gdt, memory,
-    process::{Context, Process, ProcessState},
+    gdt, interrupts, memory,
+    process::{
+        futex,
+        layout::AddressLayout,
+        signal::{self, SigAction, SignalDelivery},
+        vma::{Backing, Vma, VmaList, PAGE_SIZE, PROT_NONE, PROT_READ, PROT_WRITE},
+        wait_queue::WaitQueue,
+        Context, KernelStack, Process, Thread, ThreadState, HEAP_MAX_SIZE, MAX_FILE_DESCRIPTORS,
+    },
+    random,
 };
-use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::cmp::Ordering;
 use elfloader::ElfBinary;
 use spin::{Mutex, RwLock};
 use x86_64::{
@@ -14,15 +23,77 @@ use x86_64::{
 };
 
 pub static SCHEDULER: RwLock<Scheduler> = RwLock::new(Scheduler::new());
-static STACK_START: usize = 0x800000;
-static STACK_SIZE: usize = 0x100000;
-static HEAP_START: usize = 0x5000_0000_0000;
-static HEAP_SIZE: usize = 0x1_000_000;
+// The main stack's pages are only allocated as it grows down into them, up to
+// STACK_SIZE below the layout's stack_end
+const STACK_SIZE: usize = 0x80_0000;
+const THREAD_STACK_SIZE: usize = 0x40000;
+
+// Auxiliary vector entry types from the System V ABI
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+const AT_RANDOM: usize = 25;
+
+/// `wait_child` option to return immediately if no child has exited yet
+pub const WNOHANG: usize = 1;
+
+// `mmap` flags
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+/// What runs when no thread can: a kernel loop halting until the next interrupt
+struct IdleTask {
+    stack: KernelStack,
+    context: Context,
+}
+
+impl IdleTask {
+    fn new() -> IdleTask {
+        IdleTask {
+            stack: KernelStack::new(),
+            context: Context::default(),
+        }
+    }
+
+    /// Points the context back at the start of `idle_loop`. Nothing it was
+    /// doing when interrupted is worth keeping, so it always starts afresh.
+    fn reset(&mut self) -> *const Context {
+        let (code_selector, data_selector) = gdt::get_kernel_segments();
+        self.context = Context {
+            rip: idle_loop as usize,
+            // As if `idle_loop` had been called, keeping the stack aligned
+            rsp: self.stack.top().as_u64() as usize - 8,
+            rflags: (RFlags::INTERRUPT_FLAG | RFlags::from_bits_truncate(0x2)).bits() as usize,
+            cs: code_selector.0 as usize,
+            ss: data_selector.0 as usize,
+            ..Default::default()
+        };
+        &self.context as *const Context
+    }
+}
+
+fn idle_loop() -> ! {
+    loop {
+        // Get frames ready for user mappings while there is time to spare
+        while memory::refill_zeroed_frame() {}
+        x86_64::instructions::interrupts::enable_and_hlt();
+    }
+}
 
 pub struct Scheduler {
-    processes: RwLock<Vec<Box<Process>>>,
+    processes: RwLock<Vec<Process>>,
     cur_process: RwLock<Option<usize>>,
-    allocated_ids: RwLock<Vec<usize>>,
+    cur_thread: RwLock<usize>, // index into the current process' threads
+    allocated_ids: RwLock<Vec<usize>>, // process and thread IDs in use
+    child_exit: WaitQueue,
+    thread_exit: WaitQueue,
+    idle: Mutex<Option<IdleTask>>, // created the first time nothing is runnable
+    reaped: Mutex<Vec<Process>>,   // reaped processes, freed at the next switch
 }
 
 impl Default for Scheduler {
@@ -36,10 +107,31 @@ impl Scheduler {
         Scheduler {
             processes: RwLock::new(Vec::new()),
             cur_process: RwLock::new(None), // so that next process is 0
+            cur_thread: RwLock::new(0),
             allocated_ids: RwLock::new(Vec::new()),
+            child_exit: WaitQueue::new(),
+            thread_exit: WaitQueue::new(),
+            idle: Mutex::new(None),
+            reaped: Mutex::new(Vec::new()),
         }
     }
 
+    // Private helper returning the indices of the current process and thread
+    // Lock in the established order: processes -> cur_process -> cur_thread
+    fn current_indices(&self) -> Option<(usize, usize)> {
+        let cur_process_idx = (*self.cur_process.read())?;
+        Some((cur_process_idx, *self.cur_thread.read()))
+    }
+
+    // Private helper to find the current thread in the locked process list
+    fn current_thread<'a>(&self, processes: &'a mut [Process]) -> Option<&'a mut Thread> {
+        let (cur_process_idx, cur_thread_idx) = self.current_indices()?;
+        processes
+            .get_mut(cur_process_idx)?
+            .threads
+            .get_mut(cur_thread_idx)
+    }
+
     // Private helper to find a PID without taking a lock
     // This should only be called when the `allocated_ids` lock is already held
     fn get_available_pid_unlocked(&self, allocated_ids: &[usize]) -> usize {
@@ -58,24 +150,28 @@ impl Scheduler {
         self.get_available_pid_unlocked(&allocated)
     }
 
-    pub fn schedule(&self, file: Arc<Mutex<File>>) {
+    pub fn schedule(&self, file: Arc<Mutex<File>>, argv: &[&str], envp: &[&str]) {
         let (_current_page_table_ptr, current_page_table_physaddr) = memory::active_page_table();
         let (user_page_table_ptr, user_page_table_physaddr) = memory::create_new_user_pagetable();
 
         memory::switch_to_pagetable(user_page_table_physaddr);
 
-        let entry_point = self
+        let elf = self
             .load_elf(&file, user_page_table_ptr)
             .expect("Failed to load ELF for new process");
+        let stack_top = setup_user_stack(&elf, argv, envp);
 
         memory::switch_to_pagetable(current_page_table_physaddr);
 
         let mut process = Process::new(
-            VirtAddr::new(entry_point),
-            VirtAddr::new((STACK_START + STACK_SIZE) as u64),
+            VirtAddr::new(elf.entry_point),
+            VirtAddr::new(stack_top as u64),
             user_page_table_physaddr,
             0,
         );
+        process.vmas = elf.vmas;
+        process.layout = elf.layout;
+        process.brk = elf.layout.heap_start;
 
         // Acquire locks in the canonical order to prevent deadlocks:
         // processes -> allocated_ids
@@ -84,7 +180,7 @@ impl Scheduler {
 
         process.process_id = self.get_available_pid_unlocked(&allocated_ids);
         allocated_ids.push(process.process_id);
-        processes.push(Box::new(process));
+        processes.push(process);
     }
 
     /// Initialize a new OffsetPageTable.
@@ -95,224 +191,428 @@ impl Scheduler {
     /// This should only be called by the interrupt that is switching
     /// contexts.
     pub unsafe fn save_current_context(&self, context: *const Context) {
-        // This function should only save the context of the current process.
+        // This function should only save the context of the current thread.
         // Lock in a consistent order to prevent deadlocks: processes -> cur_process
         let mut processes = self.processes.write();
-        if let Some(cur_process_idx) = *self.cur_process.read() {
-            if cur_process_idx < processes.len() {
-                // Only save the context if the process is not already exiting
-                // If it's exiting, its context is frozen and will be removed in run_next
-                if processes[cur_process_idx].state != ProcessState::Exiting() {
-                    let ctx = (*context).clone();
-                    processes[cur_process_idx].state = ProcessState::SavedContext(ctx);
-                }
+        if let Some(thread) = self.current_thread(&mut processes) {
+            // Only save the context if the thread has not exited
+            // If it has, its context is no longer needed
+            let ctx = *context;
+            match thread.state {
+                ThreadState::Exited(_) => {}
+                ThreadState::Blocked(_) => thread.state = ThreadState::Blocked(ctx),
+                _ => thread.state = ThreadState::SavedContext(ctx),
             }
         }
     }
 
+    /// Picks the next thread to run and returns its saved context, or the idle
+    /// task's if every thread is blocked or there are none left
     pub fn run_next(&self) -> *const Context {
         // We take a write lock on processes because we might need to initialize a new process
         // by transitioning it from `StartingInfo` to `SavedContext`
         let mut processes = self.processes.write();
-        let processes_len = processes.len();
 
-        if processes_len == 0 {
-            return core::ptr::null(); // No processes to run
-        }
+        // Anything reaped last time has been switched away from by now, so its
+        // kernel stacks and address space can finally go
+        let mut reaped = self.reaped.lock();
+        reaped.clear();
 
-        // Reap any exited "zombie" processes. This is the safe place to do it,
-        // as we are in the scheduler and not running in the context of any process
-        // that might be reaped.
-        let pids_to_reap: Vec<usize> = processes
+        // Reap zombie processes that have no parent left to collect them. This is
+        // the safe place to do it, as we are in the scheduler and not running in
+        // the context of any process that might be reaped.
+        let is_orphaned_zombie = |p: &Process| p.is_zombie() && p.parent_id == 0;
+        let ids_to_reap: Vec<usize> = processes
             .iter()
-            .filter(|p| p.state == ProcessState::Exiting())
-            .map(|p| {
+            .filter(|p| is_orphaned_zombie(p))
+            .flat_map(|p| {
                 println!("Reaping process #{}", p.process_id);
-                p.process_id
+                p.ids()
             })
             .collect();
 
-        if !pids_to_reap.is_empty() {
-            // Lock allocated_ids only after we've collected the PIDs to reap
+        if !ids_to_reap.is_empty() {
+            // Lock allocated_ids only after we've collected the IDs to reap
             // This maintains the `processes` -> `allocated_ids` lock order
             let mut allocated = self.allocated_ids.write();
-            allocated.retain(|pid| !pids_to_reap.contains(pid));
-
-            processes.retain(|p| p.state != ProcessState::Exiting());
+            allocated.retain(|id| !ids_to_reap.contains(id));
+
+            // The process exiting may be the one whose kernel stack we're on,
+            // so freeing it waits until the next switch
+            let (dead, alive): (Vec<_>, Vec<_>) = core::mem::take(&mut *processes)
+                .into_iter()
+                .partition(is_orphaned_zombie);
+            *processes = alive;
+            reaped.extend(dead);
+            if processes.is_empty() {
+                println!("[Kernel] The last process has exited, idling");
+            }
         }
         let processes_len = processes.len();
+        let threads_len: usize = processes.iter().map(|p| p.threads.len()).sum();
+
+        // Look for the next runnable thread, going through each process' threads in turn
+        let mut cur_process = self.cur_process.write();
+        let mut cur_thread = self.cur_thread.write();
+        let (mut process_idx, mut thread_idx) = match *cur_process {
+            Some(current) => (current, *cur_thread + 1),
+            None => (0, 0),
+        };
+        for _ in 0..threads_len {
+            // Move on to the next process once this one's threads have had a turn
+            while process_idx >= processes_len || thread_idx >= processes[process_idx].threads.len()
+            {
+                process_idx = (process_idx + 1) % processes_len;
+                thread_idx = 0;
+            }
+            *cur_process = Some(process_idx);
+            *cur_thread = thread_idx;
 
-        // Look for the next non-exiting process to run
-        for _ in 0..processes_len {
-            // Determine and update the current process index
-            let next_idx = {
-                let mut cur_process_opt = self.cur_process.write();
-                let next = cur_process_opt.map_or(0, |current| (current + 1) % processes_len);
-                *cur_process_opt = Some(next);
-                next
-            };
-
-            let process = &mut processes[next_idx];
+            let process = &mut processes[process_idx];
+            let thread = &mut process.threads[thread_idx];
 
-            // If the process is runnable, prepare and return its context
-            if process.state != ProcessState::Exiting() {
-                // println!("Switching to process #{}", process.process_id);
+            // If the thread is runnable, prepare and return its context
+            if thread.state.is_runnable() {
+                // println!("Switching to thread #{}", thread.thread_id);
 
                 memory::switch_to_pagetable(process.page_table_phys);
+                gdt::set_kernel_stack(thread.kernel_stack.top());
 
-                // If the process is new, it's in a `StartingInfo` state
+                // If the thread is new, it's in a `StartingInfo` state
                 // We must transition it to `SavedContext` to run it
-                if let ProcessState::StartingInfo(entry_point, stack_top) = process.state {
+                if let ThreadState::StartingInfo(entry_point, stack_top) = thread.state {
                     // This is the first time we're running this process. We need to create a context that
                     // will jump to the program's entry point in user mode
                     let (code_selector, data_selector) = gdt::get_usermode_segments();
 
-                    let mut context = Context::default();
-                    context.rip = entry_point.as_u64() as usize;
-                    context.rsp = stack_top.as_u64() as usize;
-                    // CRITICAL: Bit 1 of RFLAGS is reserved and must be 1.
-                    context.rflags =
-                        (RFlags::INTERRUPT_FLAG | RFlags::from_bits_truncate(0x2)).bits() as usize;
-                    context.cs = code_selector.0 as usize;
-                    context.ss = data_selector.0 as usize;
-
-                    // Transition the process to a runnable state with the new context
-                    process.state = ProcessState::SavedContext(context);
+                    let context = Context {
+                        rip: entry_point.as_u64() as usize,
+                        rsp: stack_top.as_u64() as usize,
+                        // CRITICAL: Bit 1 of RFLAGS is reserved and must be 1.
+                        rflags: (RFlags::INTERRUPT_FLAG | RFlags::from_bits_truncate(0x2)).bits()
+                            as usize,
+                        cs: code_selector.0 as usize,
+                        ss: data_selector.0 as usize,
+                        ..Default::default()
+                    };
+
+                    // Transition the thread to a runnable state with the new context
+                    thread.state = ThreadState::SavedContext(context);
                 }
 
                 // Now we can be sure the state is `SavedContext`
                 // We then return the context to the calling interrupt to return to that context
-                let context_ptr = match &process.state {
-                    ProcessState::SavedContext(context) => context as *const Context,
+                let context_ptr = match &thread.state {
+                    ThreadState::SavedContext(context) => context as *const Context,
                     _ => unreachable!(),
                 };
 
                 return context_ptr;
             }
-            // If the process was exiting, the loop continues to the next one
+            // If the thread was blocked or has exited, the loop continues to the next one
+            thread_idx += 1;
         }
 
-        // TODO: Spin up a default process if all are exited
-        core::ptr::null()
+        // Nothing can run, so idle until an interrupt wakes something. There is
+        // no current thread meanwhile, so nothing gets saved over.
+        *cur_process = None;
+        *cur_thread = 0;
+        // Don't stay on an address space that may be torn down while idling
+        memory::switch_to_kernel_pagetable();
+        self.idle.lock().get_or_insert_with(IdleTask::new).reset()
     }
 
-    pub fn exit_current(&self) {
+    pub fn exit_current(&self, code: i32) {
         // This function is called from a syscall when a process wants to exit
-        // It marks the current process' state as `Exiting`
         // The caller (syscall handler) forces a context switch
         // immediately after this function returns
+        self.terminate_current((code & 0xff) << 8);
+    }
 
-        // Lock in the established order: processes -> cur_process to avoid deadlocks
+    /// Terminates the current process because of a signal it didn't handle.
+    /// As with `exit_current`, the caller must switch away from it afterwards.
+    pub fn kill_current(&self, signal: usize) {
+        self.terminate_current(signal as i32);
+    }
+
+    // Turns the current process into a zombie holding its wait status, encoded
+    // the way WIFEXITED and friends expect
+    fn terminate_current(&self, status: i32) {
+        let mut open_files = BTreeMap::new();
+        let mut parent_id = 0;
+        let mut thread_ids = Vec::new();
+
+        {
+            // Lock in the established order: processes -> cur_process to avoid deadlocks
+            let mut processes = self.processes.write();
+            let cur_process_opt = self.cur_process.read();
+
+            if let Some(cur_process_idx) = *cur_process_opt {
+                if cur_process_idx < processes.len() {
+                    let pid = processes[cur_process_idx].process_id;
+                    match status & 0x7f {
+                        0 => println!("Process #{} is exiting with code {}", pid, status >> 8),
+                        signal => println!("Process #{} was killed by signal {}", pid, signal),
+                    }
+
+                    let process = &mut processes[cur_process_idx];
+                    process.exit_status = Some(status);
+                    // Every thread goes down with the process
+                    for thread in process.threads.iter_mut() {
+                        thread.state = ThreadState::Exited(0);
+                        thread_ids.push(thread.thread_id);
+                    }
+                    open_files = core::mem::take(&mut process.file_descriptors);
+                    parent_id = process.parent_id;
+
+                    // Hand any children to the kernel, which reaps them once they exit
+                    for child in processes.iter_mut().filter(|p| p.parent_id == pid) {
+                        child.parent_id = 0;
+                    }
+                }
+            }
+        }
+
+        // Threads that were sleeping never get to leave their futexes themselves
+        futex::remove_threads(&thread_ids);
+        // Close the files outside the lock, as closing can wake other processes
+        drop(open_files);
+        if parent_id != 0 {
+            let _ = self.send_signal(parent_id, signal::SIGCHLD);
+        }
+        self.child_exit.wake_all();
+    }
+
+    /// Collects an exited child of the current process, returning its PID and
+    /// wait status. `pid` selects a specific child, or any child if it is not
+    /// positive. Returns `Ok((0, 0))` if `WNOHANG` is set and no child has
+    /// exited yet, or `NoChildProcess` if there is no matching child to wait for.
+    pub fn wait_child(
+        &self,
+        pid: isize,
+        options: usize,
+    ) -> Result<(usize, i32), fs::errors::Error> {
+        loop {
+            {
+                // Lock in the established order: processes -> cur_process -> allocated_ids
+                let mut processes = self.processes.write();
+                let mut cur_process = self.cur_process.write();
+                let cur_process_idx = cur_process.ok_or(fs::errors::Error::NoSuchProcess)?;
+                let cur_pid = processes
+                    .get(cur_process_idx)
+                    .ok_or(fs::errors::Error::NoSuchProcess)?
+                    .process_id;
+
+                let is_target = |p: &Process| {
+                    p.parent_id == cur_pid && (pid <= 0 || p.process_id == pid as usize)
+                };
+                if !processes.iter().any(is_target) {
+                    return Err(fs::errors::Error::NoChildProcess);
+                }
+
+                let zombie_idx = processes.iter().position(|p| is_target(p) && p.is_zombie());
+                if let Some(zombie_idx) = zombie_idx {
+                    let child = processes.remove(zombie_idx);
+                    // Removing the child shifts every process after it down by one
+                    if zombie_idx < cur_process_idx {
+                        *cur_process = Some(cur_process_idx - 1);
+                    }
+                    let child_ids: Vec<usize> = child.ids().collect();
+                    self.allocated_ids
+                        .write()
+                        .retain(|id| !child_ids.contains(id));
+
+                    let status = child.exit_status.unwrap_or(0);
+                    return Ok((child.process_id, status));
+                }
+            }
+
+            if options & WNOHANG != 0 {
+                return Ok((0, 0));
+            }
+            self.child_exit.wait()?;
+        }
+    }
+
+    /// Marks the current thread as blocked in the wait identified by `token`.
+    /// It will not be scheduled again until `wake` is called with its thread
+    /// ID, or `wake_waiter` with its thread ID and `token`.
+    pub fn block_current(&self, token: u64) {
         let mut processes = self.processes.write();
-        let cur_process_opt = self.cur_process.read();
+        if let Some(thread) = self.current_thread(&mut processes) {
+            thread.wait_token = token;
+            if let ThreadState::SavedContext(context) = &thread.state {
+                thread.state = ThreadState::Blocked(*context);
+            } else {
+                thread.state = ThreadState::Blocked(Context::default());
+            }
+        }
+    }
 
-        if let Some(cur_process_idx) = *cur_process_opt {
-            if cur_process_idx < processes.len() {
-                println!(
-                    "Process #{} is exiting",
-                    processes[cur_process_idx].process_id
-                );
-                processes[cur_process_idx].state = ProcessState::Exiting();
+    /// Makes a blocked thread runnable again, returning whether it was blocked
+    pub fn wake(&self, tid: usize) -> bool {
+        self.wake_if(tid, |_| true)
+    }
+
+    /// Like `wake`, but only if the thread is still blocked in the wait
+    /// identified by `token`. Thread IDs are reused, so a queue may hold the
+    /// ID of a thread that died waiting, now belonging to an unrelated one.
+    pub fn wake_waiter(&self, tid: usize, token: u64) -> bool {
+        self.wake_if(tid, |thread| thread.wait_token == token)
+    }
+
+    fn wake_if(&self, tid: usize, check: impl FnOnce(&Thread) -> bool) -> bool {
+        let mut processes = self.processes.write();
+        let thread = processes
+            .iter_mut()
+            .flat_map(|p| p.threads.iter_mut())
+            .find(|t| t.thread_id == tid);
+        if let Some(thread) = thread.filter(|thread| check(thread)) {
+            if let ThreadState::Blocked(context) = &thread.state {
+                thread.state = ThreadState::SavedContext(*context);
+                return true;
             }
         }
+        false
     }
 
-    pub fn fork_current(&self, context: Context) -> usize {
+    pub fn fork_current(&self, context: Context) -> Result<usize, fs::errors::Error> {
         // This function needs to read the current process and write to the process list
         // and PID list. To avoid deadlocks, we must acquire all necessary locks
         // up-front in the canonical order: processes -> cur_process -> allocated_ids
         let mut processes = self.processes.write();
-        let cur_process_opt = self.cur_process.read();
-        let (current_page_table_ptr, current_page_table_physaddr) = memory::active_page_table();
-        unsafe {
-            // FIX ME - implement copy on write later
-            memory::allocate_pages(
-                current_page_table_ptr,
-                VirtAddr::new((STACK_START + STACK_SIZE) as u64),
-                STACK_SIZE as u64,
-                PageTableFlags::PRESENT
-                    | PageTableFlags::WRITABLE
-                    | PageTableFlags::USER_ACCESSIBLE,
-            )
-            .expect("Could not allocate memory");
+        let current = self.current_indices();
 
-            let new_stack: &mut [u8] = core::slice::from_raw_parts_mut(
-                VirtAddr::new((STACK_START + STACK_SIZE) as u64).as_mut_ptr(),
-                STACK_SIZE,
-            );
-            let old_stack: &[u8] =
-                core::slice::from_raw_parts(VirtAddr::new(STACK_START as u64).as_ptr(), STACK_SIZE);
-
-            new_stack.copy_from_slice(&old_stack[..STACK_SIZE]);
-        }
-
-        if let Some(cur_process_idx) = *cur_process_opt {
+        if let Some((cur_process_idx, cur_thread_idx)) = current {
             if cur_process_idx < processes.len() {
+                // The child gets its own copy of the address space, with writable
+                // pages shared copy-on-write until either side modifies them
+                let (current_page_table_ptr, _) = memory::active_page_table();
+                let (_, child_page_table_physaddr) =
+                    memory::copy_user_pagetable(current_page_table_ptr);
+
                 let mut allocated_ids = self.allocated_ids.write();
                 let cur_process = &processes[cur_process_idx];
                 let (code_selector, data_selector) = crate::gdt::get_usermode_segments();
-                let mut ctx = context.clone();
+                let mut ctx = context;
 
                 ctx.rax = 0;
-                ctx.rsp += STACK_SIZE;
                 ctx.cs = code_selector.0 as usize;
                 ctx.ss = data_selector.0 as usize;
                 let pid = self.get_available_pid_unlocked(&allocated_ids);
 
                 allocated_ids.push(pid);
 
+                // Only the calling thread is copied into the child, where it
+                // becomes the main thread
+                let mut thread = Thread::new(pid, ThreadState::SavedContext(ctx));
+                thread.user_stack = cur_process
+                    .threads
+                    .get(cur_thread_idx)
+                    .and_then(|t| t.user_stack);
+
                 let child_process = Process {
                     process_id: pid,
-                    state: ProcessState::SavedContext(ctx),
-                    page_table_phys: current_page_table_physaddr, // Use same address space
+                    parent_id: cur_process.process_id,
+                    threads: alloc::vec![thread],
+                    exit_status: None,
+                    page_table_phys: child_page_table_physaddr,
                     file_descriptors: cur_process.file_descriptors.clone(),
-                    mmap_next_addr: cur_process.mmap_next_addr,
+                    vmas: cur_process.vmas.clone(),
+                    layout: cur_process.layout,
+                    brk: cur_process.brk,
+                    signals: cur_process.signals.forked(),
                 };
-                processes.push(Box::new(child_process));
-                return pid;
+                processes.push(child_process);
+                return Ok(pid);
             }
         }
 
-        0 // Return 0 if fork failed
+        Err(fs::errors::Error::NoSuchProcess)
     }
 
     // exec sys_call function. Differs from `schedule` as it executes on the currently running process
     // rather than creating a new process and executing on that
-    pub fn exec(&self, context: &mut Context, filename: String) -> usize {
+    pub fn exec(
+        &self,
+        context: &mut Context,
+        filename: String,
+        argv: Vec<String>,
+        envp: Vec<String>,
+    ) -> Result<usize, fs::errors::Error> {
         println!("{:?}", filename);
-        let file = fs::vfs::open(&filename).unwrap();
+        let file = fs::vfs::open(&filename, fs::file::O_RDONLY)?;
+        // Looked up before switching to the new page table, so that nothing
+        // past that point can fail without the old program being restored
+        let (cur_process_idx, cur_thread_idx) = self
+            .current_indices()
+            .ok_or(fs::errors::Error::NoSuchProcess)?;
 
+        let (_current_page_table_ptr, current_page_table_physaddr) = memory::active_page_table();
         let (user_page_table_ptr, user_page_table_physaddr) = memory::create_new_user_pagetable();
 
         memory::switch_to_pagetable(user_page_table_physaddr);
 
-        let entry_point = self
-            .load_elf(&file, user_page_table_ptr)
-            .expect("Failed to load ELF for exec");
+        let elf = match self.load_elf(&file, user_page_table_ptr) {
+            Ok(elf) => elf,
+            Err(e) => {
+                // Carry on with the old program, which is still intact
+                println!("Failed to load ELF for exec: {}", e);
+                memory::switch_to_pagetable(current_page_table_physaddr);
+                memory::free_user_pagetable(user_page_table_physaddr);
+                return Err(fs::errors::Error::NotExecutable);
+            }
+        };
 
-        context.rsp = STACK_START + STACK_SIZE;
-        context.rip = entry_point as usize;
-        context.rcx = entry_point as usize;
+        context.rsp = setup_user_stack(&elf, &argv, &envp);
+        context.rip = elf.entry_point as usize;
+        context.rcx = elf.entry_point as usize;
         let (code_selector, data_selector) = crate::gdt::get_usermode_segments();
         context.cs = code_selector.0 as usize;
         context.ss = data_selector.0 as usize;
 
-        self.cur_process.read().map(|cur_process_idx| {
-            self.processes.write()[cur_process_idx].page_table_phys = user_page_table_physaddr;
-        });
-        0
+        let (old_page_table_phys, other_threads) = {
+            let mut processes = self.processes.write();
+            let process = &mut processes[cur_process_idx];
+            let old_page_table_phys =
+                core::mem::replace(&mut process.page_table_phys, user_page_table_physaddr);
+            process.vmas = elf.vmas;
+            process.layout = elf.layout;
+            process.brk = elf.layout.heap_start;
+            process.signals.reset_handlers();
+
+            // The other threads were running the old program, so they go with it
+            let mut thread = process.threads.swap_remove(cur_thread_idx);
+            thread.user_stack = None;
+            *self.cur_thread.write() = 0;
+            let other_threads = core::mem::replace(&mut process.threads, alloc::vec![thread]);
+
+            let pid = process.process_id;
+            self.allocated_ids
+                .write()
+                .retain(|&id| id == pid || !other_threads.iter().any(|t| t.thread_id == id));
+            (old_page_table_phys, other_threads)
+        };
+        let other_ids: Vec<usize> = other_threads.iter().map(|t| t.thread_id).collect();
+        futex::remove_threads(&other_ids);
+        drop(other_threads);
+        // The new program's page table is active, so the old one can go
+        memory::free_user_pagetable(old_page_table_phys);
+        Ok(0)
     }
 
-    /// Loads an ELF file into the provided page table, allocating stack and heap.
+    /// Loads an ELF file into the provided page table and allocates its stack,
+    /// at addresses picked by a fresh `AddressLayout`. The heap starts out
+    /// empty, and is grown through `brk`.
     /// This function assumes that the provided `user_page_table_ptr` is active.
     fn load_elf(
         &self,
         file: &Arc<Mutex<File>>,
         user_page_table_ptr: *mut PageTable,
-    ) -> Result<u64, &'static str> {
+    ) -> Result<LoadedElf, &'static str> {
         // Allocate a temporary buffer to read the ELF file into.
         // TODO: Move from the heap address
-        let temp_elf_addr = VirtAddr::new(0x500000000000 as u64);
+        let temp_elf_addr = VirtAddr::new(0x5000_0000_0000);
         let temp_elf_size = file.lock().vnode.size() as u64;
 
         unsafe {
@@ -332,142 +632,857 @@ impl Scheduler {
         fs::vfs::read(file, file_buf).map_err(|_| "Failed to read ELF file")?;
 
         // Parse and load the ELF binary
+        let layout = AddressLayout::new();
         let binary = ElfBinary::new(file_buf).map_err(|_| "Failed to parse ELF file")?;
         let mut loader = elf::loader::UserspaceElfLoader {
-            vbase: 0x400000,
+            vbase: layout.load_base as u64,
             user_page_table_ptr,
+            vmas: Default::default(),
         };
         binary
             .load(&mut loader)
             .map_err(|_| "Failed to load ELF segments")?;
         let entry_point = loader.vbase + binary.entry_point();
 
+        // Find where the program headers ended up in memory, for AT_PHDR
+        let header = &binary.file.header.pt2;
+        let program_headers = binary
+            .program_headers()
+            .find(|ph| {
+                ph.offset() <= header.ph_offset()
+                    && header.ph_offset() < ph.offset() + ph.file_size()
+            })
+            .map_or(0, |ph| {
+                loader.vbase + ph.virtual_addr() + header.ph_offset() - ph.offset()
+            });
+        // Segments were writable while being filled in, now they get their own protection
+        for vma in loader.vmas.iter().filter(|v| v.prot & PROT_WRITE == 0) {
+            memory::protect_user_pages(
+                VirtAddr::new(vma.start as u64),
+                vma.len as u64,
+                vma.prot != PROT_NONE,
+                false,
+            );
+        }
+        let mut elf = LoadedElf {
+            entry_point,
+            program_headers,
+            program_header_size: header.ph_entry_size() as u64,
+            program_header_count: header.ph_count() as u64,
+            vmas: loader.vmas,
+            layout,
+        };
+
         // Deallocate the temporary buffer
         unsafe {
             memory::deallocate_pages(user_page_table_ptr, temp_elf_addr, temp_elf_size)
                 .map_err(|_| "Could not deallocate ELF buffer")?;
         }
 
-        // Allocate user stack
+        // Reserve the user stack, with a guard page below it to catch overflows
+        let stack_start = layout.stack_end - STACK_SIZE;
+        let prot = PROT_READ | PROT_WRITE;
         unsafe {
-            memory::allocate_pages(
+            memory::reserve_pages(
                 user_page_table_ptr,
-                VirtAddr::new(STACK_START as u64),
+                VirtAddr::new(stack_start as u64),
                 STACK_SIZE as u64,
-                PageTableFlags::PRESENT
-                    | PageTableFlags::WRITABLE
-                    | PageTableFlags::USER_ACCESSIBLE,
+                user_page_flags(prot),
             )
-            .map_err(|_| "Could not allocate user stack")?;
-        }
-
-        // Allocate user heap
-        unsafe {
-            memory::allocate_pages(
+            .map_err(|_| "Could not reserve user stack")?;
+            memory::add_guard_page(
                 user_page_table_ptr,
-                VirtAddr::new(HEAP_START as u64),
-                HEAP_SIZE as u64,
-                PageTableFlags::PRESENT
-                    | PageTableFlags::WRITABLE
-                    | PageTableFlags::USER_ACCESSIBLE,
-            )
-            .map_err(|_| "Could not allocate user heap")?;
+                VirtAddr::new((stack_start - PAGE_SIZE) as u64),
+            );
         }
-
-        Ok(entry_point)
+        elf.vmas.insert(Vma::anonymous(
+            stack_start - PAGE_SIZE,
+            PAGE_SIZE,
+            PROT_NONE,
+            Some("[guard]"),
+        ));
+        elf.vmas.insert(Vma::anonymous(
+            stack_start,
+            STACK_SIZE,
+            prot,
+            Some("[stack]"),
+        ));
+
+        Ok(elf)
     }
 
     pub fn push_stdin(&self, key: u8) {
-        let processes = self.processes.read();
-        // for i in 0..processes.len() {
-        if let Some(fd) = processes[0].file_descriptors.get(&0) {
-            let _ = fs::vfs::write(fd, &[key]);
+        // Clone the file out so the process list isn't locked while writing,
+        // as the write wakes any process waiting on stdin
+        let stdin = self
+            .processes
+            .read()
+            .first()
+            .and_then(|process| process.file_descriptors.get(&0).cloned());
+        if let Some(fd) = stdin {
+            let _ = fs::vfs::write(&fd, &[key]);
         }
-        // }
     }
 
-    pub fn write_file_descriptor(&self, id: u32, buf: &[u8]) {
-        self.cur_process.read().map(|cur_process_idx| {
-            if let Some(fd) = self.processes.read()[cur_process_idx]
-                .file_descriptors
-                .get(&id)
-            {
-                let _ = fs::vfs::write(fd, buf);
+    /// Returns the open file behind one of the current process' file descriptors
+    fn get_file_descriptor(&self, id: u32) -> Option<Arc<Mutex<File>>> {
+        let cur_process_idx = (*self.cur_process.read())?;
+        self.processes
+            .read()
+            .get(cur_process_idx)?
+            .file_descriptors
+            .get(&id)
+            .cloned()
+    }
+
+    pub fn write_file_descriptor(&self, id: u32, buf: &[u8]) -> Result<usize, fs::errors::Error> {
+        let fd = self
+            .get_file_descriptor(id)
+            .ok_or(fs::errors::Error::BadFileDescriptor)?;
+
+        loop {
+            match fs::vfs::write(&fd, buf) {
+                Err(fs::errors::Error::WouldBlock) => {
+                    // Sleep without holding the file lock so the reader can get in
+                    let vnode = fd.lock().vnode.clone();
+                    match vnode.write_queue() {
+                        Some(queue) => queue.wait()?,
+                        None => return Err(fs::errors::Error::WouldBlock),
+                    }
+                }
+                Err(fs::errors::Error::BrokenPipe) => {
+                    let _ = self.send_signal(self.get_cur_pid(), signal::SIGPIPE);
+                    return Err(fs::errors::Error::BrokenPipe);
+                }
+                result => return result,
             }
-        });
+        }
     }
 
-    pub fn read_file_descriptor(&self, id: u32, buf: &mut [u8]) -> isize {
-        self.cur_process
-            .read()
-            .map(|cur_process_idx| {
-                if let Some(fd) = self.processes.read()[cur_process_idx]
-                    .file_descriptors
-                    .get(&id)
-                {
-                    fs::vfs::read(fd, buf).unwrap_or(0)
-                } else {
-                    0
+    pub fn read_file_descriptor(
+        &self,
+        id: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, fs::errors::Error> {
+        let fd = self
+            .get_file_descriptor(id)
+            .ok_or(fs::errors::Error::BadFileDescriptor)?;
+
+        loop {
+            match fs::vfs::read(&fd, buf) {
+                Ok(bytes_read) => return Ok(bytes_read as usize),
+                Err(fs::errors::Error::WouldBlock) => {
+                    // Sleep without holding the file lock so the writer can get in
+                    let vnode = fd.lock().vnode.clone();
+                    match vnode.read_queue() {
+                        Some(queue) => queue.wait()?,
+                        None => return Err(fs::errors::Error::WouldBlock),
+                    }
                 }
-            })
-            .unwrap_or(0)
+                Err(e) => return Err(e),
+            }
+        }
     }
 
-    pub fn add_file_descriptor(&self, fd: &Arc<Mutex<File>>) -> usize {
-        self.cur_process
+    /// Runs `f` on the current process, holding the process list lock
+    fn with_current_process<T>(
+        &self,
+        f: impl FnOnce(&mut Process) -> Result<T, fs::errors::Error>,
+    ) -> Result<T, fs::errors::Error> {
+        // Lock in the established order: processes -> cur_process
+        let mut processes = self.processes.write();
+        let cur_process_idx = self
+            .cur_process
             .read()
-            .map(|cur_process_idx| {
-                let fd_idx: usize = self.processes.read()[cur_process_idx]
-                    .file_descriptors
-                    .len();
-                self.processes.write()[cur_process_idx]
-                    .file_descriptors
-                    .insert(fd_idx as u32, fd.clone());
-                fd_idx
-            })
-            .unwrap()
+            .ok_or(fs::errors::Error::NoSuchProcess)?;
+        let process = processes
+            .get_mut(cur_process_idx)
+            .ok_or(fs::errors::Error::NoSuchProcess)?;
+        f(process)
+    }
+
+    /// Installs an open file in the current process at the lowest free file descriptor
+    pub fn add_file_descriptor(&self, fd: &Arc<Mutex<File>>) -> Result<usize, fs::errors::Error> {
+        self.with_current_process(|process| {
+            let fd_idx = process
+                .lowest_free_fd()
+                .ok_or(fs::errors::Error::TooManyOpenFiles)?;
+            process.file_descriptors.insert(fd_idx, fd.clone());
+            Ok(fd_idx as usize)
+        })
     }
 
-    pub fn ioctl(&self, fd: usize, cmd: u32, args: usize) {
-        self.cur_process.read().map(|cur_process_idx| {
-            if let Some(file) = self.processes.read()[cur_process_idx]
+    pub fn close_file_descriptor(&self, id: u32) -> Result<(), fs::errors::Error> {
+        let file = self.with_current_process(|process| {
+            process
                 .file_descriptors
-                .get(&(fd as u32))
-            {
-                let _ = fs::vfs::ioctl(file, cmd, args);
+                .remove(&id)
+                .ok_or(fs::errors::Error::BadFileDescriptor)
+        })?;
+        // Drop the file outside the lock, as closing can wake other processes
+        drop(file);
+        Ok(())
+    }
+
+    /// Creates a pipe in the current process, returning the file descriptors of
+    /// its read and write ends
+    pub fn pipe(&self) -> Result<(usize, usize), fs::errors::Error> {
+        let (reader, writer) = fs::pipe::new();
+        let reader = Arc::new(Mutex::new(File::new(reader, true, false)));
+        let writer = Arc::new(Mutex::new(File::new(writer, false, true)));
+
+        let read_fd = self.add_file_descriptor(&reader)?;
+        match self.add_file_descriptor(&writer) {
+            Ok(write_fd) => Ok((read_fd, write_fd)),
+            Err(e) => {
+                let _ = self.close_file_descriptor(read_fd as u32);
+                Err(e)
             }
-        });
+        }
     }
 
-    pub fn mmap(&self, fd: usize, len: usize) -> Result<usize, fs::errors::Error> {
-        let mut processes = self.processes.write();
-        let cur_process_idx = self.cur_process.read().ok_or(fs::errors::Error::IoError)?;
-        if cur_process_idx >= processes.len() {
-            return Err(fs::errors::Error::IoError);
+    /// Makes a copy of a file descriptor at the lowest free file descriptor.
+    /// Both share the same open file, including its offset.
+    pub fn dup_file_descriptor(&self, id: u32) -> Result<usize, fs::errors::Error> {
+        let file = self
+            .get_file_descriptor(id)
+            .ok_or(fs::errors::Error::BadFileDescriptor)?;
+        self.add_file_descriptor(&file)
+    }
+
+    /// Makes `new_id` refer to the same open file as `old_id`, closing whatever
+    /// `new_id` referred to before
+    pub fn dup2_file_descriptor(
+        &self,
+        old_id: u32,
+        new_id: u32,
+    ) -> Result<usize, fs::errors::Error> {
+        let replaced = self.with_current_process(|process| {
+            let file = process
+                .file_descriptors
+                .get(&old_id)
+                .cloned()
+                .ok_or(fs::errors::Error::BadFileDescriptor)?;
+            if new_id >= MAX_FILE_DESCRIPTORS {
+                return Err(fs::errors::Error::BadFileDescriptor);
+            }
+            Ok(process.file_descriptors.insert(new_id, file))
+        })?;
+        drop(replaced);
+        Ok(new_id as usize)
+    }
+
+    pub fn seek_file_descriptor(
+        &self,
+        id: u32,
+        pos: fs::file::SeekFrom,
+    ) -> Result<usize, fs::errors::Error> {
+        let file = self
+            .get_file_descriptor(id)
+            .ok_or(fs::errors::Error::BadFileDescriptor)?;
+        let file = file.lock();
+        file.seek(pos)
+    }
+
+    pub fn ioctl(&self, fd: usize, cmd: u32, args: usize) -> Result<(), fs::errors::Error> {
+        let file = self
+            .get_file_descriptor(fd as u32)
+            .ok_or(fs::errors::Error::BadFileDescriptor)?;
+        fs::vfs::ioctl(&file, cmd, args)
+    }
+
+    /// Maps `len` bytes into the current process, returning where. Anonymous
+    /// mappings get fresh memory, anything else maps the device behind `fd`
+    /// from `offset` on. `addr` is taken as a hint, or as the exact place to
+    /// map at with `MAP_FIXED`, replacing whatever was there.
+    pub fn mmap(
+        &self,
+        addr: usize,
+        len: usize,
+        prot: usize,
+        flags: usize,
+        fd: usize,
+        offset: usize,
+    ) -> Result<usize, fs::errors::Error> {
+        if len == 0 || offset % PAGE_SIZE != 0 {
+            return Err(fs::errors::Error::InvalidArgument);
         }
-        let process = &mut processes[cur_process_idx];
-        let file = process
-            .file_descriptors
-            .get(&(fd as u32))
-            .ok_or(fs::errors::Error::FileDoesntExist)?;
+        let len = len
+            .checked_next_multiple_of(PAGE_SIZE)
+            .ok_or(fs::errors::Error::OutOfMemory)?;
+
+        let (backing, phys_addr) = if flags & MAP_ANONYMOUS != 0 {
+            // Forked children get copy-on-write copies, so sharing isn't possible yet
+            match flags & (MAP_SHARED | MAP_PRIVATE) {
+                MAP_PRIVATE => {}
+                MAP_SHARED => return Err(fs::errors::Error::NotImplemented),
+                _ => return Err(fs::errors::Error::InvalidArgument),
+            }
+            (Backing::Anonymous, None)
+        } else {
+            let file = self
+                .get_file_descriptor(fd as u32)
+                .ok_or(fs::errors::Error::BadFileDescriptor)?;
+            let vnode = file.lock().vnode.clone();
+            let phys_addr = vnode.mmap(offset, len)?;
+            (Backing::File { vnode, offset }, Some(phys_addr))
+        };
 
-        let file_guard = file.lock();
-        let phys_addr = file_guard.vnode.mmap(0, len)?;
+        self.with_current_process(|process| {
+            let start = if flags & MAP_FIXED != 0 {
+                let in_user_space = addr
+                    .checked_add(len)
+                    .is_some_and(|end| end <= memory::user::USER_END);
+                if addr % PAGE_SIZE != 0 || addr < PAGE_SIZE || !in_user_space {
+                    return Err(fs::errors::Error::InvalidArgument);
+                }
+                for vma in process.vmas.remove(addr, len) {
+                    unmap_user_pages(vma.start, vma.len);
+                }
+                addr
+            } else {
+                process
+                    .vmas
+                    .find_free(
+                        addr,
+                        len,
+                        process.layout.mmap_base,
+                        process.layout.heap_start,
+                    )
+                    .ok_or(fs::errors::Error::OutOfMemory)?
+            };
 
-        let virt_addr = process.mmap_next_addr;
-        crate::memory::map_physical_address_to_user(
-            VirtAddr::new(virt_addr as u64),
-            phys_addr,
-            len,
-        );
+            match phys_addr {
+                Some(phys_addr) => memory::map_physical_address_to_user(
+                    VirtAddr::new(start as u64),
+                    phys_addr,
+                    len,
+                    prot & PROT_WRITE != 0,
+                ),
+                None => reserve_user_pages(start, len, prot)?,
+            }
+            process.vmas.insert(Vma {
+                start,
+                len,
+                prot,
+                backing,
+                name: None,
+            });
+            Ok(start)
+        })
+    }
 
-        let aligned_len = (len + 0xfff) & !0xfff;
-        process.mmap_next_addr += aligned_len;
+    /// Unmaps the pages in `len` bytes from `addr` in the current process.
+    /// Parts of the range with nothing mapped are skipped over.
+    pub fn munmap(&self, addr: usize, len: usize) -> Result<(), fs::errors::Error> {
+        if addr % PAGE_SIZE != 0 || len == 0 {
+            return Err(fs::errors::Error::InvalidArgument);
+        }
+        self.with_current_process(|process| {
+            let len = len.saturating_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+            for vma in process.vmas.remove(addr, len) {
+                unmap_user_pages(vma.start, vma.len);
+            }
+            Ok(())
+        })
+    }
 
-        Ok(virt_addr)
+    /// Changes the protection of the pages in `len` bytes from `addr` in the
+    /// current process, which must all be mapped
+    pub fn mprotect(&self, addr: usize, len: usize, prot: usize) -> Result<(), fs::errors::Error> {
+        if addr % PAGE_SIZE != 0 {
+            return Err(fs::errors::Error::InvalidArgument);
+        }
+        let len = len
+            .checked_next_multiple_of(PAGE_SIZE)
+            .ok_or(fs::errors::Error::OutOfMemory)?;
+        if len == 0 {
+            return Ok(());
+        }
+        self.with_current_process(|process| {
+            process.vmas.protect(addr, len, prot)?;
+            memory::protect_user_pages(
+                VirtAddr::new(addr as u64),
+                len as u64,
+                prot != PROT_NONE,
+                prot & PROT_WRITE != 0,
+            );
+            Ok(())
+        })
+    }
+
+    /// Moves the current process' program break to `addr`, mapping or unmapping
+    /// the pages in between. Like Linux's `brk`, this returns the new break, or
+    /// the old one if it couldn't be moved, so passing 0 just reads it.
+    pub fn brk(&self, addr: usize) -> usize {
+        self.with_current_process(|process| {
+            let old_brk = process.brk;
+            let heap_start = process.layout.heap_start;
+            if !(heap_start..=heap_start + HEAP_MAX_SIZE).contains(&addr) {
+                return Ok(old_brk);
+            }
+
+            let old_end = old_brk.next_multiple_of(0x1000);
+            let new_end = addr.next_multiple_of(0x1000);
+            match new_end.cmp(&old_end) {
+                Ordering::Greater => {
+                    // The heap can't grow over anything mapped after it
+                    let prot = PROT_READ | PROT_WRITE;
+                    if !process.vmas.is_free(old_end, new_end - old_end)
+                        || reserve_user_pages(old_end, new_end - old_end, prot).is_err()
+                    {
+                        return Ok(old_brk);
+                    }
+                    process.vmas.insert(Vma::anonymous(
+                        old_end,
+                        new_end - old_end,
+                        prot,
+                        Some("[heap]"),
+                    ));
+                }
+                Ordering::Less => {
+                    for vma in process.vmas.remove(new_end, old_end - new_end) {
+                        unmap_user_pages(vma.start, vma.len);
+                    }
+                }
+                Ordering::Equal => {}
+            }
+            process.brk = addr;
+            Ok(addr)
+        })
+        .unwrap_or(0)
+    }
+
+    /// The IDs of every process, including zombies not yet waited for
+    pub fn process_ids(&self) -> Vec<usize> {
+        self.processes.read().iter().map(|p| p.process_id).collect()
+    }
+
+    /// Runs `f` on the process with ID `pid`, e.g. to report on it. The process
+    /// list is locked meanwhile, so `f` must not call back into the scheduler.
+    pub fn with_process<R>(
+        &self,
+        pid: usize,
+        f: impl FnOnce(&Process) -> R,
+    ) -> Result<R, fs::errors::Error> {
+        let processes = self.processes.read();
+        let process = processes
+            .iter()
+            .find(|p| p.process_id == pid)
+            .ok_or(fs::errors::Error::NoSuchProcess)?;
+        Ok(f(process))
     }
 
     pub fn get_cur_pid(&self) -> usize {
         self.processes.read()[self.cur_process.read().unwrap_or(0)].process_id
     }
+
+    pub fn get_cur_tid(&self) -> usize {
+        let mut processes = self.processes.write();
+        self.current_thread(&mut processes)
+            .map_or(0, |thread| thread.thread_id)
+    }
+
+    /// Starts a new thread in the current process, running `entry` with `arg`
+    /// as its argument on a stack of its own. Returns the new thread's ID.
+    pub fn spawn_thread(&self, entry: usize, arg: usize) -> Result<usize, fs::errors::Error> {
+        // A kernel or non-canonical entry would be jumped to from ring 0
+        if entry >= memory::user::USER_END {
+            return Err(fs::errors::Error::InvalidArgument);
+        }
+        self.with_current_process(|process| {
+            // Stacks come from the same region as mmap, with a guard page left
+            // below each to catch overflows
+            let guard = process
+                .vmas
+                .find_free(
+                    0,
+                    PAGE_SIZE + THREAD_STACK_SIZE,
+                    process.layout.mmap_base,
+                    process.layout.heap_start,
+                )
+                .ok_or(fs::errors::Error::OutOfMemory)?;
+            let stack_bottom = guard + PAGE_SIZE;
+            let prot = PROT_READ | PROT_WRITE;
+            reserve_user_pages(stack_bottom, THREAD_STACK_SIZE, prot)?;
+            let (page_table_ptr, _) = memory::active_page_table();
+            unsafe { memory::add_guard_page(page_table_ptr, VirtAddr::new(guard as u64)) };
+            process
+                .vmas
+                .insert(Vma::anonymous(guard, PAGE_SIZE, PROT_NONE, Some("[guard]")));
+            process.vmas.insert(Vma::anonymous(
+                stack_bottom,
+                THREAD_STACK_SIZE,
+                prot,
+                Some("[stack]"),
+            ));
+
+            let (code_selector, data_selector) = gdt::get_usermode_segments();
+            let context = Context {
+                rip: entry,
+                // Enter as if called, with a null return address on the stack
+                rsp: stack_bottom + THREAD_STACK_SIZE - 8,
+                rdi: arg,
+                rflags: (RFlags::INTERRUPT_FLAG | RFlags::from_bits_truncate(0x2)).bits() as usize,
+                cs: code_selector.0 as usize,
+                ss: data_selector.0 as usize,
+                ..Default::default()
+            };
+
+            let mut allocated_ids = self.allocated_ids.write();
+            let tid = self.get_available_pid_unlocked(&allocated_ids);
+            allocated_ids.push(tid);
+
+            let mut thread = Thread::new(tid, ThreadState::SavedContext(context));
+            thread.user_stack = Some((VirtAddr::new(stack_bottom as u64), THREAD_STACK_SIZE));
+            process.threads.push(thread);
+            Ok(tid)
+        })
+    }
+
+    /// Ends the current thread, keeping `value` for whoever joins it. The last
+    /// thread to exit takes the process with it, with `value` as the exit code.
+    /// As with `exit_current`, the caller must switch away afterwards.
+    pub fn exit_current_thread(&self, value: usize) {
+        let last_thread = {
+            let mut processes = self.processes.write();
+            let Some((cur_process_idx, cur_thread_idx)) = self.current_indices() else {
+                return;
+            };
+            let process = &mut processes[cur_process_idx];
+            let running = process
+                .threads
+                .iter()
+                .filter(|t| !matches!(t.state, ThreadState::Exited(_)))
+                .count();
+
+            if running > 1 {
+                let thread = &mut process.threads[cur_thread_idx];
+                thread.state = ThreadState::Exited(value);
+                // Nothing runs on the user stack anymore, so it can go now,
+                // along with its guard page
+                if let Some((stack_bottom, stack_size)) = thread.user_stack.take() {
+                    let guard = stack_bottom.as_u64() as usize - PAGE_SIZE;
+                    for vma in process.vmas.remove(guard, PAGE_SIZE + stack_size) {
+                        unmap_user_pages(vma.start, vma.len);
+                    }
+                }
+            }
+            running <= 1
+        };
+
+        if last_thread {
+            self.exit_current(value as i32);
+        } else {
+            self.thread_exit.wake_all();
+        }
+    }
+
+    /// Waits for a thread of the current process to exit and cleans it up,
+    /// returning the value it exited with. The main thread can't be joined.
+    pub fn join_thread(&self, tid: usize) -> Result<usize, fs::errors::Error> {
+        loop {
+            {
+                // Lock in the established order: processes -> cur_process -> cur_thread -> allocated_ids
+                let mut processes = self.processes.write();
+                let (cur_process_idx, cur_thread_idx) = self
+                    .current_indices()
+                    .ok_or(fs::errors::Error::NoSuchProcess)?;
+                let process = &mut processes[cur_process_idx];
+                if tid == process.process_id || tid == process.threads[cur_thread_idx].thread_id {
+                    return Err(fs::errors::Error::InvalidArgument);
+                }
+
+                let thread_idx = process
+                    .threads
+                    .iter()
+                    .position(|t| t.thread_id == tid)
+                    .ok_or(fs::errors::Error::NoSuchProcess)?;
+                if let ThreadState::Exited(value) = process.threads[thread_idx].state {
+                    process.threads.remove(thread_idx);
+                    // Removing the thread shifts every thread after it down by one
+                    if thread_idx < cur_thread_idx {
+                        *self.cur_thread.write() = cur_thread_idx - 1;
+                    }
+                    self.allocated_ids.write().retain(|&id| id != tid);
+                    return Ok(value);
+                }
+            }
+
+            self.thread_exit.wait()?;
+        }
+    }
+
+    /// Makes `signal` pending on a process, interrupting any blocking syscall
+    /// it is in if the signal isn't blocked. Signal 0 only checks the process
+    /// exists.
+    pub fn send_signal(&self, pid: usize, signal: usize) -> Result<(), fs::errors::Error> {
+        if signal >= signal::NSIG {
+            return Err(fs::errors::Error::InvalidArgument);
+        }
+
+        let deliverable = {
+            let mut processes = self.processes.write();
+            let process = processes
+                .iter_mut()
+                .find(|p| p.process_id == pid)
+                .ok_or(fs::errors::Error::NoSuchProcess)?;
+            // Ignored signals are discarded rather than left pending
+            if signal == 0 || process.is_zombie() || process.signals.is_ignored(signal) {
+                return Ok(());
+            }
+            process.signals.pending |= signal::sig_bit(signal);
+            process.signals.deliverable() & signal::sig_bit(signal) != 0
+        };
+
+        if deliverable {
+            // Any thread could handle it, so interrupt all of them
+            let mut processes = self.processes.write();
+            if let Some(process) = processes.iter_mut().find(|p| p.process_id == pid) {
+                for thread in process.threads.iter_mut() {
+                    if let ThreadState::Blocked(context) = &thread.state {
+                        thread.state = ThreadState::SavedContext(*context);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the current process has a signal waiting that it isn't blocking
+    pub fn has_pending_signals(&self) -> bool {
+        self.with_current_process(|process| Ok(process.signals.deliverable() != 0))
+            .unwrap_or(false)
+    }
+
+    /// Sets the current process' action for `signal` if `action` is given,
+    /// returning the previous one
+    pub fn sigaction(
+        &self,
+        signal: usize,
+        action: Option<SigAction>,
+    ) -> Result<SigAction, fs::errors::Error> {
+        if signal == 0 || signal >= signal::NSIG {
+            return Err(fs::errors::Error::InvalidArgument);
+        }
+        if action.is_some() && (signal == signal::SIGKILL || signal == signal::SIGSTOP) {
+            return Err(fs::errors::Error::InvalidArgument);
+        }
+        // Handlers are jumped to on the way back to user mode, where only user
+        // addresses belong
+        let user_end = memory::user::USER_END;
+        if action
+            .as_ref()
+            .is_some_and(|action| action.handler >= user_end || action.restorer >= user_end)
+        {
+            return Err(fs::errors::Error::InvalidArgument);
+        }
+
+        self.with_current_process(|process| {
+            let old = process.signals.action(signal);
+            if let Some(action) = action {
+                process.signals.set_action(signal, action);
+                if process.signals.is_ignored(signal) {
+                    process.signals.pending &= !signal::sig_bit(signal);
+                }
+            }
+            Ok(old)
+        })
+    }
+
+    /// Changes the current process' blocked signals as `how` says if `set` is
+    /// given, returning the previous mask
+    pub fn sigprocmask(&self, how: usize, set: Option<u64>) -> Result<u64, fs::errors::Error> {
+        self.with_current_process(|process| {
+            let old = process.signals.blocked;
+            if let Some(set) = set {
+                let blocked = match how {
+                    signal::SIG_BLOCK => old | set,
+                    signal::SIG_UNBLOCK => old & !set,
+                    signal::SIG_SETMASK => set,
+                    _ => return Err(fs::errors::Error::InvalidArgument),
+                };
+                process.signals.set_blocked(blocked);
+            }
+            Ok(old)
+        })
+    }
+
+    /// Acts on the current process' pending signals before it returns to user
+    /// mode with `context`. A caught signal rewrites `context` to run its
+    /// handler, and an unhandled fatal one terminates the process, after which
+    /// the caller must switch away from it.
+    pub fn deliver_signals(&self, context: &mut Context) -> SignalDelivery {
+        loop {
+            let next = self.with_current_process(|process| {
+                let signals = &mut process.signals;
+                let Some(signal) = signals.take_next() else {
+                    return Ok(None);
+                };
+                let action = signals.action(signal);
+                let blocked = signals.blocked;
+                if action.handler > signal::SIG_IGN {
+                    let mut mask = blocked | action.mask;
+                    if action.flags & signal::SA_NODEFER == 0 {
+                        mask |= signal::sig_bit(signal);
+                    }
+                    signals.set_blocked(mask);
+                    if action.flags & signal::SA_RESETHAND != 0 {
+                        signals.set_action(signal, SigAction::default());
+                    }
+                }
+                Ok(Some((signal, action, blocked)))
+            });
+            let Ok(Some((signal, action, blocked))) = next else {
+                return SignalDelivery::None;
+            };
+
+            match action.handler {
+                signal::SIG_IGN => continue,
+                signal::SIG_DFL => {
+                    if signal::default_action(signal) == signal::DefaultAction::Ignore {
+                        continue;
+                    }
+                    self.kill_current(signal);
+                    return SignalDelivery::Terminated;
+                }
+                _ => {
+                    // A stack the frame can't be written to leaves nowhere to run the
+                    // handler, and a handler outside user space can't be run at all
+                    if signal::push_frame(context, signal, &action, blocked).is_err() {
+                        self.kill_current(signal::SIGSEGV);
+                        return SignalDelivery::Terminated;
+                    }
+                    return SignalDelivery::Handler;
+                }
+            }
+        }
+    }
+
+    /// Returns from a signal handler to the context it interrupted, returning
+    /// the value to leave in rax
+    pub fn sigreturn(&self, context: &mut Context) -> Result<usize, fs::errors::Error> {
+        let blocked = signal::restore_frame(context)?;
+        self.with_current_process(|process| {
+            process.signals.set_blocked(blocked);
+            Ok(context.rax)
+        })
+    }
+}
+
+/// Where `load_elf` placed a program, as needed for its auxiliary vector
+struct LoadedElf {
+    entry_point: u64,
+    program_headers: u64,
+    program_header_size: u64,
+    program_header_count: u64,
+    vmas: VmaList, // the segments and stack, for the process to keep track of
+    layout: AddressLayout,
+}
+
+/// Lays out argc, argv, envp and the auxiliary vector at the top of the user
+/// stack as the System V ABI expects at program entry, returning the initial
+/// stack pointer. This function assumes the process' page table is active.
+fn setup_user_stack<S: AsRef<str>>(elf: &LoadedElf, argv: &[S], envp: &[S]) -> usize {
+    let mut sp = elf.layout.stack_end;
+
+    // Copy the strings themselves to the very top, remembering where each went
+    let mut push_str = |s: &str| {
+        sp -= s.len() + 1;
+        unsafe {
+            core::ptr::copy_nonoverlapping(s.as_ptr(), sp as *mut u8, s.len());
+            *((sp + s.len()) as *mut u8) = 0;
+        }
+        sp
+    };
+    let argv_ptrs: Vec<usize> = argv.iter().map(|s| push_str(s.as_ref())).collect();
+    let envp_ptrs: Vec<usize> = envp.iter().map(|s| push_str(s.as_ref())).collect();
+
+    // 16 bytes for AT_RANDOM, which userspace can use to seed its own generators
+    sp -= 16;
+    let random_ptr = sp;
+    unsafe { core::ptr::write_unaligned(random_ptr as *mut [u64; 2], random_seed()) };
+
+    let auxv = [
+        (AT_PHDR, elf.program_headers as usize),
+        (AT_PHENT, elf.program_header_size as usize),
+        (AT_PHNUM, elf.program_header_count as usize),
+        (AT_PAGESZ, 4096),
+        (AT_ENTRY, elf.entry_point as usize),
+        (AT_RANDOM, random_ptr),
+        (AT_NULL, 0),
+    ];
+    let words: Vec<usize> = core::iter::once(argv.len())
+        .chain(argv_ptrs)
+        .chain(core::iter::once(0))
+        .chain(envp_ptrs)
+        .chain(core::iter::once(0))
+        .chain(auxv.iter().flat_map(|&(key, value)| [key, value]))
+        .collect();
+
+    // The stack pointer must be 16 byte aligned and point at argc on entry
+    sp = (sp - words.len() * 8) & !0xf;
+    unsafe {
+        core::ptr::copy_nonoverlapping(words.as_ptr(), sp as *mut usize, words.len());
+    }
+    sp
+}
+
+/// 16 random bytes for a new program's AT_RANDOM
+fn random_seed() -> [u64; 2] {
+    [random::next_u64(), random::next_u64()]
+}
+
+/// The page table flags giving user pages the protection `prot`
+fn user_page_flags(prot: usize) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT;
+    if prot == PROT_NONE {
+        return flags | memory::NO_ACCESS;
+    }
+    flags |= PageTableFlags::USER_ACCESSIBLE;
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    flags
+}
+
+/// Reserves `len` bytes of user memory at `addr` in the active page table,
+/// with the protection `prot`. Each page gets a zeroed frame when it is first
+/// touched. Nothing is left reserved if it fails.
+fn reserve_user_pages(addr: usize, len: usize, prot: usize) -> Result<(), fs::errors::Error> {
+    let flags = user_page_flags(prot);
+    let (page_table_ptr, _) = memory::active_page_table();
+    for offset in (0..len).step_by(0x1000) {
+        let page = VirtAddr::new((addr + offset) as u64);
+        let result = unsafe { memory::reserve_pages(page_table_ptr, page, 0x1000, flags) };
+        if result.is_err() {
+            if offset > 0 {
+                unsafe {
+                    let _ = memory::deallocate_pages(
+                        page_table_ptr,
+                        VirtAddr::new(addr as u64),
+                        offset as u64,
+                    );
+                }
+            }
+            return Err(fs::errors::Error::OutOfMemory);
+        }
+    }
+    Ok(())
+}
+
+/// Unmaps `len` bytes of user memory at `addr` from the active page table
+fn unmap_user_pages(addr: usize, len: usize) {
+    let (page_table_ptr, _) = memory::active_page_table();
+    unsafe {
+        let _ = memory::deallocate_pages(page_table_ptr, VirtAddr::new(addr as u64), len as u64);
+    }
+}
+
+/// Gives up the CPU by raising the yield interrupt, which saves the current
+/// context and switches to the next runnable process
+pub fn yield_now() {
+    unsafe {
+        core::arch::asm!("int {vector}", vector = const interrupts::YIELD_VECTOR);
+    }
 }
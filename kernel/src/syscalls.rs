@@ -1,9 +1,18 @@
-use core::{arch::asm, ffi::CStr};
+use core::arch::asm;
 
-use alloc::borrow::ToOwned;
-use x86_64::VirtAddr;
+use alloc::{string::String, vec::Vec};
 
-use crate::{process::Context, scheduler};
+use crate::{
+    fs::{errors::Error, file::SeekFrom},
+    memory::user::{self, PATH_MAX},
+    process::{
+        futex,
+        signal::{SigAction, SignalDelivery},
+        Context,
+    },
+    scheduler,
+    time::{self, Timespec},
+};
 
 const MSR_STAR: usize = 0xc0000081;
 const MSR_LSTAR: usize = 0xc0000082;
@@ -59,10 +68,11 @@ macro_rules! wrap {
                 // Disable interrupts
                 "cli",
 
+                // Switch to the current process' kernel stack, which the
+                // scheduler keeps in the TSS RSP0 slot
                 "swapgs",
                 "mov gs:{tss_temp}, rsp",
-                "mov rsp, gs:{tss_timer}",
-                "sub rsp, {ks_offset}",
+                "mov rsp, gs:{tss_rsp0}",
 
                 "sub rsp, 8",
                 "push gs:{tss_temp}",
@@ -94,6 +104,8 @@ macro_rules! wrap {
                 "mov rdi, rsp",
                 // Call the hander function
                 "call {handler}",
+                // The handler returns whether the context was rewritten
+                "test al, al",
 
                 "pop r15",
                 "pop r14",
@@ -113,15 +125,20 @@ macro_rules! wrap {
                 "pop rcx",
                 "pop rbx",
                 "pop rax",
+                "jnz 2f",
 
                 "add rsp, 24",
                 "pop rsp",
 
                 "sysretq",
+
+                // sysretq takes RIP and RFLAGS from RCX and R11, so a rewritten
+                // context has to go back through the full frame instead
+                "2:",
+                "iretq",
                 handler = sym $func,
-                tss_timer = const(0x24 + crate::gdt::TIMER_INTERRUPT_INDEX * 8),
+                tss_rsp0 = const(0x04),
                 tss_temp = const(0x24 + 4 * 8),
-                ks_offset = const(1024),
                 options(noreturn)
             );
         }
@@ -133,101 +150,315 @@ wrap!(handle_syscall => wrapped_syscall_handler);
 pub const READ: usize = 0;
 pub const WRITE: usize = 1;
 pub const OPEN: usize = 2;
+pub const CLOSE: usize = 3;
+pub const LSEEK: usize = 8;
 pub const MMAP: usize = 9;
+pub const MPROTECT: usize = 10;
+pub const MUNMAP: usize = 11;
+pub const BRK: usize = 12;
+pub const RT_SIGACTION: usize = 13;
+pub const RT_SIGPROCMASK: usize = 14;
+pub const RT_SIGRETURN: usize = 15;
 pub const IOCTL: usize = 16;
+pub const PIPE: usize = 22;
+pub const SCHED_YIELD: usize = 24;
+pub const DUP: usize = 32;
+pub const DUP2: usize = 33;
+pub const NANOSLEEP: usize = 35;
 pub const GET_PID: usize = 39;
+pub const THREAD_SPAWN: usize = 56;
 pub const FORK: usize = 57;
 pub const EXEC: usize = 59;
 pub const EXIT: usize = 60;
+pub const WAIT4: usize = 61;
+pub const KILL: usize = 62;
+pub const GET_TID: usize = 186;
+pub const FUTEX: usize = 202;
+pub const CLOCK_GETTIME: usize = 228;
+// Linux does these through clone and futexes, so they have numbers of their own
+pub const THREAD_EXIT: usize = 400;
+pub const THREAD_JOIN: usize = 401;
+
+// `lseek` whence values
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+// `clock_gettime` clocks. Both count from boot, as there is no wall clock yet
+pub const CLOCK_MONOTONIC: usize = 1;
+pub const CLOCK_BOOTTIME: usize = 7;
+
+/// Most bytes of argument or environment strings that `exec` will copy
+const ARG_MAX: usize = 0x10000;
+
+/// Most bytes moved by a single read or write, which are staged in a kernel
+/// buffer. Callers have to cope with short reads and writes anyway.
+const IO_MAX: usize = 0x10000;
+
+/// Copies a null-terminated array of C strings, such as `exec`'s argv, out of
+/// userspace. A null `ptr` is treated as an empty array.
+fn read_string_array(ptr: usize) -> Result<Vec<String>, Error> {
+    let mut strings = Vec::new();
+    let mut total_len = 0;
+    if ptr == 0 {
+        return Ok(strings);
+    }
+
+    let mut entry = ptr;
+    loop {
+        let string_ptr: usize = user::read_from_user(entry)?;
+        if string_ptr == 0 {
+            return Ok(strings);
+        }
+        let string =
+            user::read_user_string(string_ptr, ARG_MAX - total_len).map_err(|e| match e {
+                Error::NameTooLong => Error::ArgumentListTooLong,
+                e => e,
+            })?;
+        total_len += string.len() + 1;
+        strings.push(string);
+        entry += core::mem::size_of::<usize>();
+    }
+}
 
+/// Reads an optional argument of type `T` from userspace, where a null `ptr`
+/// means it wasn't given
+fn read_optional<T: Copy>(ptr: usize) -> Result<Option<T>, Error> {
+    match ptr {
+        0 => Ok(None),
+        ptr => user::read_from_user(ptr).map(Some),
+    }
+}
+
+/// Handles the syscall whose registers were saved in `regs`, returning whether
+/// the context was rewritten, e.g. to run a signal handler
 // fn handle_syscall(stack_frame: &mut InterruptStackFrame, regs: &mut Context) {
-fn handle_syscall(regs: &mut Context) {
+extern "C" fn handle_syscall(regs: &mut Context) -> bool {
     // println!("{:?}", regs);
 
-    match regs.rax {
+    let syscall = regs.rax;
+    let result = match syscall {
         READ => {
-            let buf: &mut [u8] =
-                unsafe { core::slice::from_raw_parts_mut(regs.rsi as *mut u8, regs.rdx) };
-            regs.rax = scheduler::SCHEDULER
-                .read()
-                .read_file_descriptor(regs.rdi as u32, buf) as usize;
+            let mut buf = alloc::vec![0; regs.rdx.min(IO_MAX)];
+            // Check the destination before reading, so no data is lost to a bad pointer
+            user::check_range(regs.rsi, buf.len(), true).and_then(|_| {
+                let bytes_read = scheduler::SCHEDULER
+                    .read()
+                    .read_file_descriptor(regs.rdi as u32, &mut buf)?;
+                user::copy_to_user(regs.rsi, &buf[..bytes_read])?;
+                Ok(bytes_read)
+            })
         }
-        WRITE => unsafe {
-            let slice: &[u8] =
-                core::slice::from_raw_parts(VirtAddr::new(regs.rsi as u64).as_ptr(), regs.rdx);
-            if regs.rdi == 1 {
-                let string = core::str::from_utf8(slice).unwrap();
-                print!("{string}");
-            } else {
+        WRITE => {
+            let mut buf = alloc::vec![0; regs.rdx.min(IO_MAX)];
+            user::copy_from_user(&mut buf, regs.rsi).and_then(|_| {
                 scheduler::SCHEDULER
                     .read()
-                    .write_file_descriptor(regs.rdi as u32, slice);
-            }
-            regs.rax = 0;
-        },
-        OPEN => {
-            let filename = unsafe { CStr::from_ptr(VirtAddr::new(regs.rdi as u64).as_ptr()) }
-                .to_str()
-                .unwrap()
-                .to_owned();
-
-            match crate::fs::vfs::open(&filename) {
-                Ok(fd) => {
-                    regs.rax = scheduler::SCHEDULER.read().add_file_descriptor(&fd);
-                }
-                Err(_) => {
-                    regs.rax = usize::MAX;
-                }
-            }
+                    .write_file_descriptor(regs.rdi as u32, &buf)
+            })
+        }
+        OPEN => user::read_user_string(regs.rdi, PATH_MAX)
+            .and_then(|filename| crate::fs::vfs::open(&filename, regs.rsi))
+            .and_then(|fd| scheduler::SCHEDULER.read().add_file_descriptor(&fd)),
+        CLOSE => scheduler::SCHEDULER
+            .read()
+            .close_file_descriptor(regs.rdi as u32)
+            .map(|_| 0),
+        LSEEK => {
+            let pos = match regs.rdx {
+                SEEK_SET => usize::try_from(regs.rsi as isize)
+                    .map(SeekFrom::Start)
+                    .map_err(|_| Error::InvalidArgument),
+                SEEK_CUR => Ok(SeekFrom::Current(regs.rsi as isize)),
+                SEEK_END => Ok(SeekFrom::End(regs.rsi as isize)),
+                _ => Err(Error::InvalidArgument),
+            };
+            pos.and_then(|pos| {
+                scheduler::SCHEDULER
+                    .read()
+                    .seek_file_descriptor(regs.rdi as u32, pos)
+            })
+        }
+        MMAP => scheduler::SCHEDULER
+            .read()
+            .mmap(regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9),
+        MPROTECT => scheduler::SCHEDULER
+            .read()
+            .mprotect(regs.rdi, regs.rsi, regs.rdx)
+            .map(|_| 0),
+        MUNMAP => scheduler::SCHEDULER
+            .read()
+            .munmap(regs.rdi, regs.rsi)
+            .map(|_| 0),
+        BRK => Ok(scheduler::SCHEDULER.read().brk(regs.rdi)),
+        RT_SIGACTION => {
+            // The last argument is the size of the signal mask, which must match ours
+            let action = match regs.r10 {
+                8 => read_optional::<SigAction>(regs.rsi),
+                _ => Err(Error::InvalidArgument),
+            };
+            action
+                .and_then(|action| scheduler::SCHEDULER.read().sigaction(regs.rdi, action))
+                .and_then(|old| {
+                    if regs.rdx != 0 {
+                        user::write_to_user(regs.rdx, &old)?;
+                    }
+                    Ok(0)
+                })
+        }
+        RT_SIGPROCMASK => {
+            let set = match regs.r10 {
+                8 => read_optional::<u64>(regs.rsi),
+                _ => Err(Error::InvalidArgument),
+            };
+            set.and_then(|set| scheduler::SCHEDULER.read().sigprocmask(regs.rdi, set))
+                .and_then(|old| {
+                    if regs.rdx != 0 {
+                        user::write_to_user(regs.rdx, &old)?;
+                    }
+                    Ok(0)
+                })
         }
-        MMAP => match scheduler::SCHEDULER.read().mmap(regs.r8, regs.rsi) {
-            Ok(addr) => {
-                regs.rax = addr;
+        RT_SIGRETURN => {
+            let result = scheduler::SCHEDULER.read().sigreturn(regs);
+            if result.is_err() {
+                // The frame was clobbered, so there is nothing sane to return to
+                scheduler::SCHEDULER
+                    .read()
+                    .kill_current(crate::process::signal::SIGSEGV);
+                scheduler::yield_now();
+                unreachable!();
             }
-            Err(_) => {
-                regs.rax = 0;
+            result
+        }
+        IOCTL => scheduler::SCHEDULER
+            .read()
+            .ioctl(regs.rdi, regs.rsi as u32, regs.rdx)
+            .map(|_| 0),
+        PIPE => scheduler::SCHEDULER
+            .read()
+            .pipe()
+            .and_then(|(read_fd, write_fd)| {
+                let fds = [read_fd as i32, write_fd as i32];
+                user::write_to_user(regs.rdi, &fds).map(|_| 0).map_err(|e| {
+                    let scheduler = scheduler::SCHEDULER.read();
+                    let _ = scheduler.close_file_descriptor(read_fd as u32);
+                    let _ = scheduler.close_file_descriptor(write_fd as u32);
+                    e
+                })
+            }),
+        DUP => scheduler::SCHEDULER
+            .read()
+            .dup_file_descriptor(regs.rdi as u32),
+        DUP2 => scheduler::SCHEDULER
+            .read()
+            .dup2_file_descriptor(regs.rdi as u32, regs.rsi as u32),
+        SCHED_YIELD => {
+            scheduler::yield_now();
+            Ok(0)
+        }
+        NANOSLEEP => user::read_from_user::<Timespec>(regs.rdi).and_then(|request| {
+            let duration = request.to_duration().ok_or(Error::InvalidArgument)?;
+            let deadline = time::deadline_after(duration);
+            time::sleep_until(deadline).inspect_err(|e| {
+                // Let the caller carry on where it left off
+                if *e == Error::Interrupted && regs.rsi != 0 {
+                    let _ = user::write_to_user(regs.rsi, &Timespec::from(time::until(deadline)));
+                }
+            })?;
+            Ok(0)
+        }),
+        CLOCK_GETTIME => match regs.rdi {
+            CLOCK_MONOTONIC | CLOCK_BOOTTIME => {
+                user::write_to_user(regs.rsi, &Timespec::from(time::uptime())).map(|_| 0)
             }
+            _ => Err(Error::InvalidArgument),
         },
-        IOCTL => {
-            // FIX ME - expand to actually check arguments rather than just assume we are getting
-            // framebuffer info
-            scheduler::SCHEDULER.read().ioctl(
-                regs.rdi as usize,
-                regs.rsi as u32,
-                regs.rdx as usize,
-            );
-            // look at return values
-        }
-        GET_PID => {
-            regs.rax = scheduler::SCHEDULER.read().get_cur_pid();
+        GET_PID => Ok(scheduler::SCHEDULER.read().get_cur_pid()),
+        GET_TID => Ok(scheduler::SCHEDULER.read().get_cur_tid()),
+        THREAD_SPAWN => scheduler::SCHEDULER.read().spawn_thread(regs.rdi, regs.rsi),
+        THREAD_EXIT => {
+            scheduler::SCHEDULER.read().exit_current_thread(regs.rdi);
+            scheduler::yield_now();
+
+            unreachable!();
         }
+        THREAD_JOIN => scheduler::SCHEDULER
+            .read()
+            .join_thread(regs.rdi)
+            .and_then(|value| {
+                if regs.rsi != 0 {
+                    user::write_to_user(regs.rsi, &value)?;
+                }
+                Ok(0)
+            }),
         FORK => {
             println!(
                 "[Kernel] Forking PID: {}",
                 scheduler::SCHEDULER.read().get_cur_pid()
             );
-            regs.rax = scheduler::SCHEDULER.read().fork_current(regs.clone());
+            scheduler::SCHEDULER.read().fork_current(*regs)
         }
         EXEC => {
-            let filename = unsafe { CStr::from_ptr(VirtAddr::new(regs.rdi as u64).as_ptr()) }
-                .to_str()
-                .unwrap()
-                .to_owned();
-            regs.rax = scheduler::SCHEDULER.read().exec(regs, filename);
+            // Copy the arguments out now, as exec replaces the address space they live in
+            let args = user::read_user_string(regs.rdi, PATH_MAX).and_then(|filename| {
+                Ok((
+                    filename,
+                    read_string_array(regs.rsi)?,
+                    read_string_array(regs.rdx)?,
+                ))
+            });
+            args.and_then(|(filename, argv, envp)| {
+                scheduler::SCHEDULER.read().exec(regs, filename, argv, envp)
+            })
         }
         EXIT => {
-            // Mark the current process as exiting.
-            scheduler::SCHEDULER.read().exit_current();
+            // Mark the current process as exited, keeping its code for the parent
+            scheduler::SCHEDULER.read().exit_current(regs.rdi as i32);
 
             // This process must not run anymore. We force a context switch
-            // by triggering a Timer interrupt which runs our context switching
-            // logic
-            unsafe {
-                asm!("int 32", options(nomem, nostack));
-            }
+            // by yielding, which runs our context switching logic
+            scheduler::yield_now();
+
+            unreachable!();
+        }
+        WAIT4 => scheduler::SCHEDULER
+            .read()
+            .wait_child(regs.rdi as isize, regs.rdx)
+            .and_then(|(pid, status)| {
+                if pid != 0 && regs.rsi != 0 {
+                    user::write_to_user(regs.rsi, &status)?;
+                }
+                Ok(pid)
+            }),
+        FUTEX => match regs.rsi & !futex::FUTEX_PRIVATE_FLAG {
+            // Timeouts aren't supported yet
+            futex::FUTEX_WAIT if regs.r10 != 0 => Err(Error::NotImplemented),
+            futex::FUTEX_WAIT => futex::wait(regs.rdi, regs.rdx as u32),
+            futex::FUTEX_WAKE => futex::wake(regs.rdi, regs.rdx),
+            _ => Err(Error::NotImplemented),
+        },
+        KILL => scheduler::SCHEDULER
+            .read()
+            .send_signal(regs.rdi, regs.rsi)
+            .map(|_| 0),
+        _ => Err(Error::NotImplemented),
+    };
+
+    // Failures are returned as a negated error number, like Linux
+    regs.rax = match result {
+        Ok(value) => value,
+        Err(e) => e.errno().wrapping_neg(),
+    };
 
+    // Handle any signals before going back to user mode
+    let delivery = scheduler::SCHEDULER.read().deliver_signals(regs);
+    match delivery {
+        SignalDelivery::None => syscall == RT_SIGRETURN,
+        SignalDelivery::Handler => true,
+        SignalDelivery::Terminated => {
+            scheduler::yield_now();
             unreachable!();
         }
-        _ => {}
     }
 }
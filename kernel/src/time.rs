@@ -0,0 +1,127 @@
+//! The system timer. The PIT raises IRQ 0 at a fixed rate, which drives both
+//! the scheduler and the uptime clock.
+
+use crate::{fs::errors::Error, outb, process::wait_queue::WaitQueue, scheduler};
+use alloc::vec::Vec;
+use core::{
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+use spin::Mutex;
+
+/// Timer interrupts per second, which is also the scheduler's quantum
+pub const TICK_HZ: u32 = 100;
+
+/// The PIT's input clock, which the divisor is applied to
+const PIT_FREQUENCY: u32 = 1_193_182;
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 0, low then high byte of the divisor, mode 2 (rate generator)
+const PIT_RATE_GENERATOR: u8 = 0x34;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// The rate the PIT was actually set to, as the divisor rounds it
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+
+/// Threads sleeping until a tick count, along with their thread IDs. They park
+/// on `SLEEP_QUEUE`, but are woken one by one as their deadlines pass.
+static SLEEPERS: Mutex<Vec<(u64, usize)>> = Mutex::new(Vec::new());
+static SLEEP_QUEUE: WaitQueue = WaitQueue::new();
+
+/// A length of time as `nanosleep` and `clock_gettime` take it
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl Timespec {
+    /// Converts to a `Duration`, if this is a valid, non-negative time
+    pub fn to_duration(self) -> Option<Duration> {
+        let secs = u64::try_from(self.tv_sec).ok()?;
+        let nanos = u32::try_from(self.tv_nsec).ok()?;
+        (nanos < NANOS_PER_SEC as u32).then(|| Duration::new(secs, nanos))
+    }
+}
+
+impl From<Duration> for Timespec {
+    fn from(duration: Duration) -> Self {
+        Timespec {
+            tv_sec: duration.as_secs() as i64,
+            tv_nsec: duration.subsec_nanos() as i64,
+        }
+    }
+}
+
+/// Programs the PIT to interrupt `frequency` times a second
+pub fn init(frequency: u32) {
+    let divisor = (PIT_FREQUENCY / frequency.max(1)).clamp(1, u16::MAX as u32);
+    FREQUENCY.store(PIT_FREQUENCY / divisor, Ordering::Relaxed);
+
+    outb(PIT_COMMAND, PIT_RATE_GENERATOR);
+    outb(PIT_CHANNEL_0, divisor as u8);
+    outb(PIT_CHANNEL_0, (divisor >> 8) as u8);
+}
+
+/// Counts a timer interrupt, waking any threads whose sleep is over. Called
+/// from the timer interrupt handler.
+pub fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+
+    let mut expired = Vec::new();
+    SLEEPERS.lock().retain(|&(deadline, tid)| {
+        if deadline <= now {
+            expired.push(tid);
+        }
+        deadline > now
+    });
+
+    let scheduler = scheduler::SCHEDULER.read();
+    for tid in expired {
+        scheduler.wake(tid);
+    }
+}
+
+/// Timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * NANOS_PER_SEC / FREQUENCY.load(Ordering::Relaxed).max(1) as u128;
+    Duration::from_nanos(nanos as u64)
+}
+
+/// Time since the timer was started, to the nearest tick
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+/// The tick by which at least `duration` will have passed
+pub fn deadline_after(duration: Duration) -> u64 {
+    let frequency = FREQUENCY.load(Ordering::Relaxed) as u128;
+    let length = (duration.as_nanos() * frequency).div_ceil(NANOS_PER_SEC);
+    // The current tick is already partly over, so it can't count
+    ticks().saturating_add(length as u64).saturating_add(1)
+}
+
+/// Time left until `deadline`
+pub fn until(deadline: u64) -> Duration {
+    ticks_to_duration(deadline.saturating_sub(ticks()))
+}
+
+/// Puts the current thread to sleep until the tick count reaches `deadline`.
+/// Returns `Interrupted` if a signal arrives first.
+pub fn sleep_until(deadline: u64) -> Result<(), Error> {
+    let tid = scheduler::SCHEDULER.read().get_cur_tid();
+    while ticks() < deadline {
+        SLEEPERS.lock().push((deadline, tid));
+        let result = SLEEP_QUEUE.wait();
+        SLEEPERS.lock().retain(|&(_, sleeper)| sleeper != tid);
+        result?;
+    }
+    Ok(())
+}
@@ -0,0 +1,119 @@
+//! The arguments, environment and auxiliary vector the kernel lays out on the
+//! stack when a program starts.
+
+use core::{
+    ffi::{c_char, CStr},
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+// Auxiliary vector entry types from the System V ABI
+pub const AT_NULL: usize = 0;
+pub const AT_PHDR: usize = 3;
+pub const AT_PHENT: usize = 4;
+pub const AT_PHNUM: usize = 5;
+pub const AT_PAGESZ: usize = 6;
+pub const AT_ENTRY: usize = 9;
+pub const AT_RANDOM: usize = 25;
+
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicPtr<*const c_char> = AtomicPtr::new(core::ptr::null_mut());
+static ENVP: AtomicPtr<*const c_char> = AtomicPtr::new(core::ptr::null_mut());
+static AUXV: AtomicPtr<usize> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Records where everything is, given the initial stack pointer which points
+/// at argc.
+///
+/// # Safety
+///
+/// `stack` must be the stack pointer the kernel started the program with.
+pub(crate) unsafe fn init(stack: *const usize) {
+    let argc = *stack;
+    let argv = stack.add(1) as *mut *const c_char;
+    let envp = argv.add(argc + 1);
+
+    // The auxiliary vector starts after the null that ends envp
+    let mut envp_end = envp;
+    while !(*envp_end).is_null() {
+        envp_end = envp_end.add(1);
+    }
+
+    ARGC.store(argc, Ordering::Relaxed);
+    ARGV.store(argv, Ordering::Relaxed);
+    ENVP.store(envp, Ordering::Relaxed);
+    AUXV.store(envp_end.add(1) as *mut usize, Ordering::Relaxed);
+}
+
+unsafe fn to_str(ptr: *const c_char) -> &'static str {
+    CStr::from_ptr(ptr).to_str().unwrap_or("")
+}
+
+/// Iterator over the program's arguments, returned by `args`
+pub struct Args {
+    index: usize,
+}
+
+impl Iterator for Args {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= ARGC.load(Ordering::Relaxed) {
+            return None;
+        }
+        let arg = unsafe { to_str(*ARGV.load(Ordering::Relaxed).add(self.index)) };
+        self.index += 1;
+        Some(arg)
+    }
+}
+
+/// Returns the arguments the program was started with, starting with its path
+pub fn args() -> Args {
+    Args { index: 0 }
+}
+
+/// Iterator over the program's environment variables, returned by `env`
+pub struct Env {
+    next: *const *const c_char,
+}
+
+impl Iterator for Env {
+    type Item = (&'static str, &'static str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next.is_null() || unsafe { (*self.next).is_null() } {
+            return None;
+        }
+        let var = unsafe { to_str(*self.next) };
+        self.next = unsafe { self.next.add(1) };
+        Some(var.split_once('=').unwrap_or((var, "")))
+    }
+}
+
+/// Returns the program's environment variables as `(key, value)` pairs
+pub fn env() -> Env {
+    Env {
+        next: ENVP.load(Ordering::Relaxed),
+    }
+}
+
+/// Looks up a single environment variable
+pub fn var(key: &str) -> Option<&'static str> {
+    env().find(|&(k, _)| k == key).map(|(_, v)| v)
+}
+
+/// Looks up an entry of the auxiliary vector, e.g. `AT_PAGESZ`
+pub fn getauxval(kind: usize) -> Option<usize> {
+    let mut entry = AUXV.load(Ordering::Relaxed) as *const usize;
+    if entry.is_null() {
+        return None;
+    }
+    loop {
+        let (key, value) = unsafe { (*entry, *entry.add(1)) };
+        if key == AT_NULL {
+            return None;
+        }
+        if key == kind {
+            return Some(value);
+        }
+        entry = unsafe { entry.add(2) };
+    }
+}
@@ -0,0 +1,93 @@
+use core::fmt;
+
+/// The error number a failed syscall returned
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub usize);
+
+impl Errno {
+    pub const EPERM: Errno = Errno(1);
+    pub const ENOENT: Errno = Errno(2);
+    pub const ESRCH: Errno = Errno(3);
+    pub const EINTR: Errno = Errno(4);
+    pub const EIO: Errno = Errno(5);
+    pub const E2BIG: Errno = Errno(7);
+    pub const ENOEXEC: Errno = Errno(8);
+    pub const EBADF: Errno = Errno(9);
+    pub const ECHILD: Errno = Errno(10);
+    pub const EAGAIN: Errno = Errno(11);
+    pub const ENOMEM: Errno = Errno(12);
+    pub const EFAULT: Errno = Errno(14);
+    pub const EEXIST: Errno = Errno(17);
+    pub const ENOTDIR: Errno = Errno(20);
+    pub const EISDIR: Errno = Errno(21);
+    pub const EINVAL: Errno = Errno(22);
+    pub const EMFILE: Errno = Errno(24);
+    pub const ENOTTY: Errno = Errno(25);
+    pub const EROFS: Errno = Errno(30);
+    pub const EPIPE: Errno = Errno(32);
+    pub const ENAMETOOLONG: Errno = Errno(36);
+    pub const ENOSYS: Errno = Errno(38);
+
+    /// Decodes a raw syscall return value, where -4095..=-1 are negated error numbers
+    pub fn from_ret(ret: usize) -> Result<usize, Errno> {
+        if ret > (-4096isize) as usize {
+            Err(Errno(ret.wrapping_neg()))
+        } else {
+            Ok(ret)
+        }
+    }
+
+    /// The symbolic name of the error, e.g. `ENOENT`
+    pub fn name(&self) -> Option<&'static str> {
+        self.info().map(|(name, _)| name)
+    }
+
+    /// A short human readable description of the error
+    pub fn description(&self) -> &'static str {
+        self.info()
+            .map_or("Unknown error", |(_, description)| description)
+    }
+
+    fn info(&self) -> Option<(&'static str, &'static str)> {
+        Some(match *self {
+            Errno::EPERM => ("EPERM", "Operation not permitted"),
+            Errno::ENOENT => ("ENOENT", "No such file or directory"),
+            Errno::ESRCH => ("ESRCH", "No such process"),
+            Errno::EINTR => ("EINTR", "Interrupted system call"),
+            Errno::EIO => ("EIO", "Input/output error"),
+            Errno::E2BIG => ("E2BIG", "Argument list too long"),
+            Errno::ENOEXEC => ("ENOEXEC", "Exec format error"),
+            Errno::EBADF => ("EBADF", "Bad file descriptor"),
+            Errno::ECHILD => ("ECHILD", "No child processes"),
+            Errno::EAGAIN => ("EAGAIN", "Resource temporarily unavailable"),
+            Errno::ENOMEM => ("ENOMEM", "Cannot allocate memory"),
+            Errno::EFAULT => ("EFAULT", "Bad address"),
+            Errno::EEXIST => ("EEXIST", "File exists"),
+            Errno::ENOTDIR => ("ENOTDIR", "Not a directory"),
+            Errno::EISDIR => ("EISDIR", "Is a directory"),
+            Errno::EINVAL => ("EINVAL", "Invalid argument"),
+            Errno::EMFILE => ("EMFILE", "Too many open files"),
+            Errno::ENOTTY => ("ENOTTY", "Inappropriate ioctl for device"),
+            Errno::EROFS => ("EROFS", "Read-only file system"),
+            Errno::EPIPE => ("EPIPE", "Broken pipe"),
+            Errno::ENAMETOOLONG => ("ENAMETOOLONG", "File name too long"),
+            Errno::ENOSYS => ("ENOSYS", "Function not implemented"),
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Debug for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{name}"),
+            None => write!(f, "Errno({})", self.0),
+        }
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (os error {})", self.description(), self.0)
+    }
+}
@@ -0,0 +1,79 @@
+//! The global allocator. The heap starts out empty and is grown with `brk`
+//! whenever an allocation doesn't fit.
+
+use crate::{sync::Mutex, syscalls::brk};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{self, NonNull},
+};
+use linked_list_allocator::Heap;
+
+/// The least the heap grows by at a time, to keep `brk` calls rare
+const GROWTH: usize = 0x10_0000;
+
+pub struct BrkHeap {
+    heap: Mutex<Heap>,
+}
+
+impl BrkHeap {
+    pub const fn new() -> BrkHeap {
+        BrkHeap {
+            heap: Mutex::new(Heap::empty()),
+        }
+    }
+}
+
+impl Default for BrkHeap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Moves the program break up to make room for `layout`, handing the new
+/// memory to `heap`. Returns false if the kernel wouldn't move it.
+fn grow(heap: &mut Heap, layout: Layout) -> bool {
+    // Leave room to align the allocation within the new memory
+    let needed = (layout.size() + layout.align()).next_multiple_of(GROWTH);
+    // Nothing else moves the break, so the heap always ends at it
+    let old_end = match heap.size() {
+        0 => brk(0),
+        _ => heap.top(),
+    };
+    let Some(new_end) = old_end.checked_add(needed) else {
+        return false;
+    };
+    if brk(new_end) != new_end {
+        return false;
+    }
+
+    unsafe {
+        match heap.size() {
+            0 => heap.init(old_end, needed),
+            _ => heap.extend(needed),
+        }
+    }
+    true
+}
+
+unsafe impl GlobalAlloc for BrkHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.heap.lock();
+        if let Ok(ptr) = heap.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+        if !grow(&mut heap, layout) {
+            return ptr::null_mut();
+        }
+        heap.allocate_first_fit(layout)
+            .map_or(ptr::null_mut(), |ptr| ptr.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(ptr) = NonNull::new(ptr) {
+            self.heap.lock().deallocate(ptr, layout);
+        }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: BrkHeap = BrkHeap::new();
@@ -1,39 +1,51 @@
 #![no_std]
+#![feature(naked_functions)]
+
+extern crate alloc;
 
 #[macro_use]
 pub mod print;
+pub mod env;
+pub mod errno;
+pub mod heap;
+pub mod signal;
+pub mod sync;
 pub mod syscalls;
+pub mod thread;
 
 use core::panic::PanicInfo;
 #[panic_handler]
 pub fn panic(info: &PanicInfo) -> ! {
     println!("App panic!\n{:?}", info);
-    unsafe {
-        syscalls::exit();
-    };
-    loop {}
+    syscalls::exit(101)
 }
 
 extern "C" {
-    fn main() -> ();
+    fn main();
 }
 
+/// Program entry point. The kernel leaves the stack pointer on argc, so pass
+/// it to `start` before anything gets pushed.
+///
+/// # Safety
+///
+/// Only the kernel may jump here, with the stack laid out as the System V ABI
+/// describes for program entry.
+#[naked]
 #[no_mangle]
-pub unsafe extern "C" fn _start() {
-    init_heap();
-    #[cfg(not(test))]
-    main();
-    syscalls::exit();
+pub unsafe extern "C" fn _start() -> ! {
+    core::arch::asm!(
+        "mov rdi, rsp",
+        "xor rbp, rbp",
+        "call {start}",
+        start = sym start,
+        options(noreturn)
+    );
 }
 
-use linked_list_allocator::LockedHeap;
-
-#[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
-pub fn init_heap() {
-    let heap_start = 0x5000_0000_0000;
-    let heap_size = 0x1_000_000;
-    unsafe {
-        ALLOCATOR.lock().init(heap_start, heap_size);
-    }
+unsafe extern "C" fn start(stack: *const usize) -> ! {
+    env::init(stack);
+    #[cfg(not(test))]
+    main();
+    syscalls::exit(0);
 }
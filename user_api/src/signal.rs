@@ -0,0 +1,129 @@
+use crate::errno::Errno;
+use crate::syscalls::{syscall2, syscall4, KILL, RT_SIGACTION, RT_SIGPROCMASK};
+
+// Signal numbers
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGABRT: usize = 6;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGURG: usize = 23;
+pub const SIGWINCH: usize = 28;
+
+// Special handler values
+pub const SIG_DFL: usize = 0;
+pub const SIG_IGN: usize = 1;
+
+// `SigAction` flags
+pub const SA_RESTORER: usize = 0x0400_0000;
+pub const SA_NODEFER: usize = 0x4000_0000;
+pub const SA_RESETHAND: usize = 0x8000_0000;
+
+// `sigprocmask` operations
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+/// The bit representing a signal in a signal mask
+pub const fn sig_bit(signal: usize) -> u64 {
+    1 << (signal - 1)
+}
+
+/// A function to run when a signal arrives, which is passed the signal number
+pub type Handler = extern "C" fn(usize);
+
+/// How a process handles a signal, as `rt_sigaction` takes it
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SigAction {
+    pub handler: usize,
+    pub flags: usize,
+    pub restorer: usize,
+    /// Signals blocked while the handler runs
+    pub mask: u64,
+}
+
+impl SigAction {
+    pub fn new(handler: Handler) -> SigAction {
+        SigAction {
+            handler: handler as usize,
+            ..SigAction::default()
+        }
+    }
+}
+
+/// Where handlers return to. The kernel left the interrupted context on the
+/// stack, and `rt_sigreturn` goes back to it.
+#[naked]
+unsafe extern "C" fn restore_rt() -> ! {
+    core::arch::asm!(
+        "mov rax, 15", // RT_SIGRETURN
+        "syscall",
+        options(noreturn)
+    );
+}
+
+/// Sends a signal to a process. Signal 0 only checks the process exists.
+pub fn kill(pid: usize, signal: usize) -> Result<(), Errno> {
+    Errno::from_ret(unsafe { syscall2(KILL, pid, signal) }).map(|_| ())
+}
+
+/// Changes how a signal is handled if `action` is given, returning the
+/// previous action
+pub fn sigaction(signal: usize, action: Option<&SigAction>) -> Result<SigAction, Errno> {
+    let action = action.map(|action| SigAction {
+        flags: action.flags | SA_RESTORER,
+        restorer: restore_rt as usize,
+        ..*action
+    });
+    let mut old = SigAction::default();
+    let action_ptr = action
+        .as_ref()
+        .map_or(0, |action| action as *const SigAction as usize);
+    Errno::from_ret(unsafe {
+        syscall4(
+            RT_SIGACTION,
+            signal,
+            action_ptr,
+            &mut old as *mut SigAction as usize,
+            core::mem::size_of::<u64>(),
+        )
+    })?;
+    Ok(old)
+}
+
+/// Runs `handler` whenever `signal` arrives
+pub fn signal(signal: usize, handler: Handler) -> Result<(), Errno> {
+    sigaction(signal, Some(&SigAction::new(handler))).map(|_| ())
+}
+
+/// Changes the blocked signals as `how` says if `set` is given, returning the
+/// previous mask
+pub fn sigprocmask(how: usize, set: Option<u64>) -> Result<u64, Errno> {
+    let mut old = 0u64;
+    let set_ptr = set.as_ref().map_or(0, |set| set as *const u64 as usize);
+    Errno::from_ret(unsafe {
+        syscall4(
+            RT_SIGPROCMASK,
+            how,
+            set_ptr,
+            &mut old as *mut u64 as usize,
+            core::mem::size_of::<u64>(),
+        )
+    })?;
+    Ok(old)
+}
@@ -0,0 +1,148 @@
+//! Blocking locks built on futexes. An uncontended lock or unlock is a single
+//! atomic operation, and the kernel is only asked to sleep or wake threads
+//! when there is contention.
+
+use crate::syscalls::{futex_wait, futex_wake};
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+// Mutex states
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+/// Locked, and there may be threads sleeping on the futex
+const CONTENDED: u32 = 2;
+
+/// A mutual exclusion lock whose waiters sleep instead of spinning
+pub struct Mutex<T: ?Sized> {
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Mutex<T> {
+        Mutex {
+            state: AtomicU32::new(UNLOCKED),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Locks the mutex, sleeping until it is available
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // Mark it contended, so the holder knows to wake us when unlocking
+            while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+                let _ = futex_wait(&self.state, CONTENDED);
+            }
+        }
+        MutexGuard { mutex: self }
+    }
+
+    /// Locks the mutex only if that can be done without waiting
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            let _ = futex_wake(&self.state, 1);
+        }
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Mutex::new(T::default())
+    }
+}
+
+/// Access to the data behind a locked `Mutex`, which is unlocked when this is
+/// dropped
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// Lets threads sleep until another thread notifies them that something
+/// guarded by a `Mutex` has changed
+pub struct Condvar {
+    /// Bumped on every notification, so a waiter can tell if it missed one
+    sequence: AtomicU32,
+}
+
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar {
+            sequence: AtomicU32::new(0),
+        }
+    }
+
+    /// Unlocks the mutex and sleeps until notified, locking it again before
+    /// returning. Wake ups can be spurious, so callers should wait in a loop
+    /// that checks their condition.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        let mutex = guard.mutex;
+        drop(guard);
+
+        // If a notification came in since unlocking, the sequence has moved on
+        // and this returns straight away
+        let _ = futex_wait(&self.sequence, sequence);
+        mutex.lock()
+    }
+
+    /// Wakes one waiting thread
+    pub fn notify_one(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        let _ = futex_wake(&self.sequence, 1);
+    }
+
+    /// Wakes every waiting thread
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        let _ = futex_wake(&self.sequence, i32::MAX as usize);
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Condvar::new()
+    }
+}
@@ -1,34 +1,112 @@
+use crate::errno::Errno;
+use alloc::vec::Vec;
+use core::{sync::atomic::AtomicU32, time::Duration};
+
 pub const READ: usize = 0;
 pub const WRITE: usize = 1;
 pub const OPEN: usize = 2;
+pub const CLOSE: usize = 3;
+pub const LSEEK: usize = 8;
 pub const MMAP: usize = 9;
+pub const MPROTECT: usize = 10;
+pub const MUNMAP: usize = 11;
+pub const BRK: usize = 12;
+pub const RT_SIGACTION: usize = 13;
+pub const RT_SIGPROCMASK: usize = 14;
+pub const RT_SIGRETURN: usize = 15;
 pub const IOCTL: usize = 16;
+pub const PIPE: usize = 22;
+pub const SCHED_YIELD: usize = 24;
+pub const DUP: usize = 32;
+pub const DUP2: usize = 33;
+pub const NANOSLEEP: usize = 35;
 pub const GET_PID: usize = 39;
+pub const THREAD_SPAWN: usize = 56;
 pub const FORK: usize = 57;
 pub const EXEC: usize = 59;
 pub const EXIT: usize = 60;
+pub const WAIT4: usize = 61;
+pub const KILL: usize = 62;
+pub const GET_TID: usize = 186;
+pub const FUTEX: usize = 202;
+pub const CLOCK_GETTIME: usize = 228;
+pub const THREAD_EXIT: usize = 400;
+pub const THREAD_JOIN: usize = 401;
 
-pub unsafe fn read(fd: usize, buf: &mut [u8]) -> isize {
-    let r0;
-    core::arch::asm!(
-        "syscall",
-        inlateout("rax") READ => r0,
-        in("rdi") fd,
-        in("rsi") buf.as_ptr(),
-        in("rdx") buf.len(),
-        options(nostack, preserves_flags)
-    );
-    r0
+// `open` flags
+pub const O_RDONLY: usize = 0;
+pub const O_WRONLY: usize = 1;
+pub const O_RDWR: usize = 2;
+pub const O_CREAT: usize = 0o100;
+pub const O_EXCL: usize = 0o200;
+pub const O_TRUNC: usize = 0o1000;
+pub const O_APPEND: usize = 0o2000;
+
+// `mmap` protection
+pub const PROT_NONE: usize = 0x0;
+pub const PROT_READ: usize = 0x1;
+pub const PROT_WRITE: usize = 0x2;
+pub const PROT_EXEC: usize = 0x4;
+
+// `mmap` flags
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+// `lseek` whence values
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+/// `wait4` option to return 0 immediately if no child has exited yet
+pub const WNOHANG: usize = 1;
+
+// `futex` operations
+pub const FUTEX_WAIT: usize = 0;
+pub const FUTEX_WAKE: usize = 1;
+
+// `clock_gettime` clocks, which both count from boot
+pub const CLOCK_MONOTONIC: usize = 1;
+pub const CLOCK_BOOTTIME: usize = 7;
+
+/// A length of time as `nanosleep` and `clock_gettime` take it
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl From<Duration> for Timespec {
+    fn from(duration: Duration) -> Self {
+        Timespec {
+            tv_sec: duration.as_secs() as i64,
+            tv_nsec: duration.subsec_nanos() as i64,
+        }
+    }
+}
+
+impl From<Timespec> for Duration {
+    fn from(time: Timespec) -> Self {
+        Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+    }
 }
 
-pub unsafe fn write(fd: usize, buf: &[u8]) -> isize {
+// Raw syscalls, returning whatever the kernel left in rax. `syscall` itself
+// overwrites rcx and r11 with the return address and flags
+
+/// Makes syscall `n` with no arguments
+///
+/// # Safety
+///
+/// Syscall `n` must not take any arguments, or it would use whatever was
+/// left in the argument registers.
+pub unsafe fn syscall0(n: usize) -> usize {
     let r0;
     core::arch::asm!(
         "syscall",
-        inlateout("rax") WRITE => r0,
-        in("rdi") fd,
-        in("rsi") buf.as_ptr(),
-        in("rdx") buf.len(),
+        inlateout("rax") n => r0,
         lateout("rcx") _,
         lateout("r11") _,
         options(nostack, preserves_flags)
@@ -36,80 +114,329 @@ pub unsafe fn write(fd: usize, buf: &[u8]) -> isize {
     r0
 }
 
-pub unsafe fn open(filename: &[u8]) -> usize {
+/// Makes syscall `n` with one argument
+///
+/// # Safety
+///
+/// The arguments must be what syscall `n` expects. Any pointers among them must be
+/// valid for the kernel to read or write as that syscall does.
+pub unsafe fn syscall1(n: usize, a1: usize) -> usize {
     let r0;
     core::arch::asm!(
         "syscall",
-        inlateout("rax") OPEN => r0,
-        in("rdi") filename.as_ptr(), // Filename pointer
-        in("rsi") 0, // Flags
-        in("rdx") 0, // mode
+        inlateout("rax") n => r0,
+        in("rdi") a1,
+        lateout("rcx") _,
+        lateout("r11") _,
         options(nostack, preserves_flags)
     );
     r0
 }
 
-pub unsafe fn mmap(ptr: usize, len: usize, fd: usize) -> usize {
+/// Makes syscall `n` with two arguments
+///
+/// # Safety
+///
+/// The arguments must be what syscall `n` expects. Any pointers among them must be
+/// valid for the kernel to read or write as that syscall does.
+pub unsafe fn syscall2(n: usize, a1: usize, a2: usize) -> usize {
     let r0;
     core::arch::asm!(
         "syscall",
-        inlateout("rax") MMAP => r0,
-        in("rdi") ptr, // pointer
-        in("rsi") len, // length
-        in("r8") fd, // file descriptor
+        inlateout("rax") n => r0,
+        in("rdi") a1,
+        in("rsi") a2,
+        lateout("rcx") _,
+        lateout("r11") _,
         options(nostack, preserves_flags)
     );
     r0
 }
 
-pub unsafe fn ioctl(fd: usize, cmd: u32, arg: usize) {
-    core::arch::asm!(
-        "syscall",
-        in("rax") IOCTL,
-        in("rdi") fd, // File descriptor
-        in("rsi") cmd, // Command
-        in("rdx") arg, // Argument
-        options(nostack, preserves_flags)
-    );
-}
-
-pub unsafe fn get_pid() -> isize {
+/// Makes syscall `n` with three arguments
+///
+/// # Safety
+///
+/// The arguments must be what syscall `n` expects. Any pointers among them must be
+/// valid for the kernel to read or write as that syscall does.
+pub unsafe fn syscall3(n: usize, a1: usize, a2: usize, a3: usize) -> usize {
     let r0;
     core::arch::asm!(
         "syscall",
-        inlateout("rax") GET_PID => r0,
+        inlateout("rax") n => r0,
+        in("rdi") a1,
+        in("rsi") a2,
+        in("rdx") a3,
+        lateout("rcx") _,
+        lateout("r11") _,
         options(nostack, preserves_flags)
     );
     r0
 }
 
-pub unsafe fn fork() -> isize {
+/// Makes syscall `n` with four arguments
+///
+/// # Safety
+///
+/// The arguments must be what syscall `n` expects. Any pointers among them must be
+/// valid for the kernel to read or write as that syscall does.
+pub unsafe fn syscall4(n: usize, a1: usize, a2: usize, a3: usize, a4: usize) -> usize {
     let r0;
     core::arch::asm!(
         "syscall",
-        inlateout("rax") FORK => r0,
+        inlateout("rax") n => r0,
+        in("rdi") a1,
+        in("rsi") a2,
+        in("rdx") a3,
+        in("r10") a4,
+        lateout("rcx") _,
+        lateout("r11") _,
         options(nostack, preserves_flags)
     );
     r0
 }
 
-// FIX ME - implement args
-pub unsafe fn exec(filename: &[u8]) -> isize {
+/// Makes syscall `n` with six arguments
+///
+/// # Safety
+///
+/// The arguments must be what syscall `n` expects. Any pointers among them must be
+/// valid for the kernel to read or write as that syscall does.
+pub unsafe fn syscall6(
+    n: usize,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+    a4: usize,
+    a5: usize,
+    a6: usize,
+) -> usize {
     let r0;
     core::arch::asm!(
         "syscall",
-        in("rdi") filename.as_ptr(),
-        inlateout("rax") EXEC => r0,
+        inlateout("rax") n => r0,
+        in("rdi") a1,
+        in("rsi") a2,
+        in("rdx") a3,
+        in("r10") a4,
+        in("r8") a5,
+        in("r9") a6,
+        lateout("rcx") _,
+        lateout("r11") _,
         options(nostack, preserves_flags)
     );
     r0
 }
 
-pub unsafe fn exit() {
-    core::arch::asm!(
-        "syscall",
-        in("rax") EXIT,
-        in("rdi") 0,
-        options(nostack, preserves_flags)
-    );
+/// Checks a string is null-terminated before its pointer is handed to the kernel
+fn c_str(s: &[u8]) -> Result<*const u8, Errno> {
+    match s.last() {
+        Some(0) => Ok(s.as_ptr()),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize, Errno> {
+    Errno::from_ret(unsafe { syscall3(READ, fd, buf.as_mut_ptr() as usize, buf.len()) })
+}
+
+pub fn write(fd: usize, buf: &[u8]) -> Result<usize, Errno> {
+    Errno::from_ret(unsafe { syscall3(WRITE, fd, buf.as_ptr() as usize, buf.len()) })
+}
+
+/// Opens a file with `O_*` flags, returning its file descriptor. `filename`
+/// must be null-terminated.
+pub fn open(filename: &[u8], flags: usize) -> Result<usize, Errno> {
+    let filename = c_str(filename)?;
+    Errno::from_ret(unsafe { syscall3(OPEN, filename as usize, flags, 0) })
+}
+
+pub fn close(fd: usize) -> Result<(), Errno> {
+    Errno::from_ret(unsafe { syscall1(CLOSE, fd) }).map(|_| ())
+}
+
+/// Moves a file's offset relative to `whence`, one of `SEEK_SET`, `SEEK_CUR` or
+/// `SEEK_END`, returning the new offset from the start of the file
+pub fn lseek(fd: usize, offset: isize, whence: usize) -> Result<usize, Errno> {
+    Errno::from_ret(unsafe { syscall3(LSEEK, fd, offset as usize, whence) })
+}
+
+/// Creates a pipe, returning its read and write file descriptors. Reads return
+/// 0 once every copy of the write end has been closed.
+pub fn pipe() -> Result<(usize, usize), Errno> {
+    let mut fds = [0i32; 2];
+    Errno::from_ret(unsafe { syscall1(PIPE, fds.as_mut_ptr() as usize) })?;
+    Ok((fds[0] as usize, fds[1] as usize))
+}
+
+/// Duplicates a file descriptor onto the lowest free one, sharing its offset
+pub fn dup(fd: usize) -> Result<usize, Errno> {
+    Errno::from_ret(unsafe { syscall1(DUP, fd) })
+}
+
+/// Makes `new_fd` refer to the same file as `old_fd`, closing it first if needed
+pub fn dup2(old_fd: usize, new_fd: usize) -> Result<usize, Errno> {
+    Errno::from_ret(unsafe { syscall2(DUP2, old_fd, new_fd) })
+}
+
+/// Maps `len` bytes of a file into memory, or fresh memory if `flags` has
+/// `MAP_ANONYMOUS`, returning the address it was mapped at. `ptr` is a hint
+/// for where to put it, unless `flags` has `MAP_FIXED`.
+pub fn mmap(
+    ptr: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    fd: usize,
+    offset: usize,
+) -> Result<usize, Errno> {
+    Errno::from_ret(unsafe { syscall6(MMAP, ptr, len, prot, flags, fd, offset) })
+}
+
+/// Unmaps the pages in `len` bytes from `ptr`
+pub fn munmap(ptr: usize, len: usize) -> Result<usize, Errno> {
+    Errno::from_ret(unsafe { syscall2(MUNMAP, ptr, len) })
+}
+
+/// Changes the protection of the pages in `len` bytes from `ptr` to `prot`
+pub fn mprotect(ptr: usize, len: usize, prot: usize) -> Result<usize, Errno> {
+    Errno::from_ret(unsafe { syscall3(MPROTECT, ptr, len, prot) })
+}
+
+/// Moves the end of the heap to `addr`, returning where it ends up. It stays
+/// put if it can't be moved, and passing 0 just returns where it is.
+pub fn brk(addr: usize) -> usize {
+    unsafe { syscall1(BRK, addr) }
+}
+
+/// Performs a device specific operation on a file.
+///
+/// # Safety
+///
+/// Depending on `cmd`, the kernel may write through `arg` as a pointer, so it
+/// must be valid for whatever the device expects.
+pub unsafe fn ioctl(fd: usize, cmd: u32, arg: usize) -> Result<usize, Errno> {
+    Errno::from_ret(syscall3(IOCTL, fd, cmd as usize, arg))
+}
+
+pub fn get_pid() -> usize {
+    unsafe { syscall0(GET_PID) }
+}
+
+/// Creates a copy of the current process, returning 0 in the child and the
+/// child's PID in the parent
+pub fn fork() -> Result<usize, Errno> {
+    Errno::from_ret(unsafe { syscall0(FORK) })
+}
+
+/// Replaces the current program, only returning if that failed. `filename` and
+/// every argument and environment string must be null-terminated, and argv
+/// should start with the program path.
+pub fn exec(filename: &[u8], argv: &[&[u8]], envp: &[&[u8]]) -> Errno {
+    let to_ptrs = |strings: &[&[u8]]| -> Result<Vec<*const u8>, Errno> {
+        strings
+            .iter()
+            .map(|s| c_str(s))
+            .chain(core::iter::once(Ok(core::ptr::null())))
+            .collect()
+    };
+    let args = c_str(filename).and_then(|filename| Ok((filename, to_ptrs(argv)?, to_ptrs(envp)?)));
+    let (filename, argv, envp) = match args {
+        Ok(args) => args,
+        Err(e) => return e,
+    };
+
+    let ret = unsafe {
+        syscall3(
+            EXEC,
+            filename as usize,
+            argv.as_ptr() as usize,
+            envp.as_ptr() as usize,
+        )
+    };
+    Errno::from_ret(ret).err().unwrap_or(Errno::EINVAL)
+}
+
+pub fn exit(code: i32) -> ! {
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            in("rax") EXIT,
+            in("rdi") code,
+            options(noreturn, nostack)
+        );
+    }
+}
+
+/// Waits for a child to exit, returning its PID. `pid` selects a specific child,
+/// or any child if it is -1. How it ended can be read from `status` with
+/// `exit_status` or `term_signal`.
+pub fn wait4(pid: isize, status: &mut i32, options: usize) -> Result<usize, Errno> {
+    Errno::from_ret(unsafe {
+        syscall4(WAIT4, pid as usize, status as *mut i32 as usize, options, 0)
+    })
+}
+
+/// Extracts the exit code from a status filled in by `wait4`
+pub fn exit_status(status: i32) -> i32 {
+    (status >> 8) & 0xff
+}
+
+/// Extracts the signal that killed a child from a status filled in by `wait4`,
+/// or 0 if it exited normally
+pub fn term_signal(status: i32) -> i32 {
+    status & 0x7f
+}
+
+/// Sleeps until woken through `futex_wake`, as long as `word` still holds
+/// `expected`. Fails with `EAGAIN` if it doesn't.
+pub fn futex_wait(word: &AtomicU32, expected: u32) -> Result<(), Errno> {
+    Errno::from_ret(unsafe {
+        syscall4(
+            FUTEX,
+            word.as_ptr() as usize,
+            FUTEX_WAIT,
+            expected as usize,
+            0,
+        )
+    })
+    .map(|_| ())
+}
+
+/// Wakes up to `count` threads sleeping on `word`, returning how many woke
+pub fn futex_wake(word: &AtomicU32, count: usize) -> Result<usize, Errno> {
+    Errno::from_ret(unsafe { syscall4(FUTEX, word.as_ptr() as usize, FUTEX_WAKE, count, 0) })
+}
+
+/// Gives up the rest of this thread's time slice
+pub fn sched_yield() {
+    unsafe { syscall0(SCHED_YIELD) };
+}
+
+/// Sleeps for at least `duration`. If a signal interrupts it this fails with
+/// `EINTR`, leaving how long was left in `remaining`.
+pub fn nanosleep(duration: Duration, remaining: Option<&mut Duration>) -> Result<(), Errno> {
+    let request = Timespec::from(duration);
+    let mut left = Timespec::default();
+    let ret = unsafe {
+        syscall2(
+            NANOSLEEP,
+            &request as *const Timespec as usize,
+            &mut left as *mut Timespec as usize,
+        )
+    };
+    Errno::from_ret(ret).map(|_| ()).inspect_err(|&e| {
+        if let (Errno::EINTR, Some(remaining)) = (e, remaining) {
+            *remaining = left.into();
+        }
+    })
+}
+
+/// Reads one of the `CLOCK_*` clocks
+pub fn clock_gettime(clock: usize) -> Result<Duration, Errno> {
+    let mut time = Timespec::default();
+    Errno::from_ret(unsafe {
+        syscall2(CLOCK_GETTIME, clock, &mut time as *mut Timespec as usize)
+    })?;
+    Ok(time.into())
 }
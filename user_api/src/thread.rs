@@ -0,0 +1,102 @@
+use crate::errno::Errno;
+use crate::syscalls::{self, syscall0, syscall2, GET_TID, THREAD_EXIT, THREAD_JOIN, THREAD_SPAWN};
+use alloc::{boxed::Box, sync::Arc};
+use core::{cell::UnsafeCell, time::Duration};
+
+/// Where a thread leaves its result for `join`
+struct Packet<T> {
+    result: UnsafeCell<Option<T>>,
+}
+
+// The thread writes the result before exiting, and `join` only reads it once
+// the kernel says the thread has exited
+unsafe impl<T: Send> Sync for Packet<T> {}
+
+/// An owned permission to join a thread. A thread that is never joined keeps
+/// its ID and kernel stack until the process exits.
+pub struct JoinHandle<T> {
+    tid: usize,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// The spawned thread's ID
+    pub fn id(&self) -> usize {
+        self.tid
+    }
+
+    /// Waits for the thread to finish, returning what its closure returned
+    pub fn join(self) -> Result<T, Errno> {
+        let mut value = 0usize;
+        Errno::from_ret(unsafe {
+            syscall2(THREAD_JOIN, self.tid, &mut value as *mut usize as usize)
+        })?;
+        let result = unsafe { (*self.packet.result.get()).take() };
+        // Panics take the whole process down, so a joined thread always has a result
+        Ok(result.expect("joined thread has no result"))
+    }
+}
+
+type Main = Box<dyn FnOnce()>;
+
+/// Runs `f` on a new thread sharing this process' memory and files
+pub fn spawn<F, T>(f: F) -> Result<JoinHandle<T>, Errno>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let packet = Arc::new(Packet {
+        result: UnsafeCell::new(None),
+    });
+    let their_packet = packet.clone();
+    let main: Main = Box::new(move || {
+        let result = f();
+        unsafe { *their_packet.result.get() = Some(result) };
+    });
+    // Box it again, as the thread only gets a thin pointer
+    let arg = Box::into_raw(Box::new(main)) as usize;
+
+    match Errno::from_ret(unsafe { syscall2(THREAD_SPAWN, thread_start as usize, arg) }) {
+        Ok(tid) => Ok(JoinHandle { tid, packet }),
+        Err(e) => {
+            drop(unsafe { Box::from_raw(arg as *mut Main) });
+            Err(e)
+        }
+    }
+}
+
+/// Entry point of spawned threads, which the kernel passes the boxed closure
+extern "C" fn thread_start(arg: usize) -> ! {
+    let main = unsafe { Box::from_raw(arg as *mut Main) };
+    main();
+    exit(0)
+}
+
+/// Ends the current thread. If it is the last one, the process exits with
+/// `value` as its exit code.
+fn exit(value: usize) -> ! {
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            in("rax") THREAD_EXIT,
+            in("rdi") value,
+            options(noreturn, nostack)
+        );
+    }
+}
+
+/// The ID of the calling thread. The main thread's is the process ID.
+pub fn current_id() -> usize {
+    unsafe { syscall0(GET_TID) }
+}
+
+/// Lets other threads run before this one carries on
+pub fn yield_now() {
+    syscalls::sched_yield();
+}
+
+/// Puts the current thread to sleep for at least `duration`, going back to
+/// sleep if a signal handler interrupts it
+pub fn sleep(mut duration: Duration) {
+    while let Err(Errno::EINTR) = syscalls::nanosleep(duration, Some(&mut duration)) {}
+}
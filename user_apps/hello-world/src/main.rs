@@ -4,20 +4,57 @@
 #[macro_use]
 extern crate user_api;
 
+use user_api::syscalls;
+
 #[no_mangle]
 fn main() {
-    let mut pid = unsafe { user_api::syscalls::get_pid() };
+    let mut pid = syscalls::get_pid();
     println!("[{pid}] Forking...");
 
-    let fork_ret = unsafe { user_api::syscalls::fork() };
+    // The child's output comes back to us through a pipe
+    let (read_fd, write_fd) = syscalls::pipe().expect("Could not create pipe");
+    let fork_ret = syscalls::fork();
+
+    pid = syscalls::get_pid();
+
+    match fork_ret {
+        Ok(0) => {
+            println!("[{pid}] Child");
+            let _ = syscalls::dup2(write_fd, 1);
+            let _ = syscalls::close(read_fd);
+            let _ = syscalls::close(write_fd);
+            // With no stdin, test-binary exits once it has printed its arguments
+            let _ = syscalls::close(0);
+
+            let err = syscalls::exec(
+                b"/initrd/test-binary\0",
+                &[b"/initrd/test-binary\0", b"hello\0"],
+                &[b"PARENT=hello-world\0"],
+            );
+            println!("[{pid}] exec failed: {err}");
+        }
+        Ok(child) => {
+            println!("[{pid}] Parent");
+            let _ = syscalls::close(write_fd);
 
-    pid = unsafe { user_api::syscalls::get_pid() };
+            let mut buf = [0; 64];
+            while let Ok(len @ 1..) = syscalls::read(read_fd, &mut buf) {
+                let output = core::str::from_utf8(&buf[..len]).unwrap_or("?");
+                print!("[{pid}] child #{child} said: {output}");
+            }
 
-    if fork_ret == 0 {
-        println!("[{pid}] Child");
-        let _exec_ret = unsafe { user_api::syscalls::exec(b"/initrd/test-binary\0") };
-    } else {
-        println!("[{pid}] Parent");
+            let mut status = 0;
+            if syscalls::wait4(child as isize, &mut status, 0).is_ok() {
+                match syscalls::term_signal(status) {
+                    0 => {
+                        let code = syscalls::exit_status(status);
+                        println!("[{pid}] child #{child} exited with {code}");
+                    }
+                    signal => println!("[{pid}] child #{child} was killed by signal {signal}"),
+                }
+            }
+        }
+        Err(err) => println!("[{pid}] fork failed: {err}"),
     }
 
     println!("[{pid}] This prints once now!");
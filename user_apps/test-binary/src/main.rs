@@ -80,13 +80,34 @@ fn main() {
     // user_api::syscalls::write(fd, &mut buf);
     // println!("{buf:?}");
     // }
+    check_bad_thread_entries();
+
+    for (i, arg) in user_api::env::args().enumerate() {
+        println!("argv[{i}] = {arg}");
+    }
+    for (key, value) in user_api::env::env() {
+        println!("{key}={value}");
+    }
+
     loop {
         let mut x: [u8; 1] = [0; 1];
-        unsafe {
-            user_api::syscalls::read(0, &mut x);
-        };
-        if x != [0] {
-            print!("{}", x[0] as char);
+        match user_api::syscalls::read(0, &mut x) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => print!("{}", x[0] as char),
         }
     }
 }
+
+/// Spawning a thread at a kernel or non-canonical address has to be refused
+/// rather than crash the kernel on the way to user mode
+fn check_bad_thread_entries() {
+    use user_api::{errno::Errno, syscalls};
+    for entry in [0xffff_8000_0000_0000, 0x0000_8000_0000_0000] {
+        let ret = unsafe { syscalls::syscall2(syscalls::THREAD_SPAWN, entry, 0) };
+        assert_eq!(
+            Errno::from_ret(ret).err(),
+            Some(Errno::EINVAL),
+            "thread spawned at {entry:#x}"
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use alloc::{sync::Arc, vec::Vec};
 use lazy_static::lazy_static;
-use spin::Mutex;
+use user_api::sync::Mutex;
 
 lazy_static! {
     pub static ref MOUSE_EVENT: Mutex<MouseEventHandler> = Mutex::new(MouseEventHandler::new());
@@ -13,29 +13,29 @@ pub struct MouseEventHandler {
 
 impl MouseEventHandler {
     pub fn new() -> Self {
-        let fd = unsafe { user_api::syscalls::open(b"/dev/mouse\0") };
+        let fd = user_api::syscalls::open(b"/dev/mouse\0", user_api::syscalls::O_RDONLY)
+            .expect("Could not open mouse");
         MouseEventHandler {
             fd,
             listeners: Vec::new(),
         }
     }
 
+    /// Waits for mouse activity and dispatches every queued event to the listeners.
+    /// Packets are written to /dev/mouse 3 bytes at a time, so reading into a
+    /// multiple of 3 bytes always yields whole packets.
     pub fn poll(&self) {
-        loop {
-            let mut mouse_buf: [u8; 3] = [0; 3];
-            let bytes_read = unsafe { user_api::syscalls::read(self.fd, &mut mouse_buf) };
-
-            let _ = mouse_buf == [0; 3]; // Fix me - weird bug where without this bytes_read = 0 even if they are read
-
-            if bytes_read <= 0 {
-                break;
-            }
+        let mut mouse_buf: [u8; 3 * 16] = [0; 3 * 16];
+        let Ok(bytes_read) = user_api::syscalls::read(self.fd, &mut mouse_buf) else {
+            return;
+        };
 
+        for packet in mouse_buf[..bytes_read].chunks_exact(3) {
             let e = MouseEvent {
-                x_delta: mouse_buf[1] as i8,
-                y_delta: mouse_buf[2] as i8,
-                left: (mouse_buf[0] & 0x1) != 0,
-                right: (mouse_buf[0] & 0x2) != 0,
+                x_delta: packet[1] as i8,
+                y_delta: packet[2] as i8,
+                left: (packet[0] & 0x1) != 0,
+                right: (packet[0] & 0x2) != 0,
             };
 
             for listener in &self.listeners {
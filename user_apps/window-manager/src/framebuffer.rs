@@ -1,5 +1,6 @@
 #![allow(dead_code)]
 use core::slice;
+use user_api::syscalls::{MAP_SHARED, PROT_READ, PROT_WRITE};
 
 #[derive(Debug, Clone, Copy, Default)]
 pub struct FrameBufferInfo {
@@ -58,15 +59,17 @@ pub struct FrameBuffer {
 
 impl FrameBuffer {
     pub fn new(fd: usize) -> Self {
-        let info: FrameBufferInfo = FrameBufferInfo::default();
+        let mut info: FrameBufferInfo = FrameBufferInfo::default();
 
-        let ptr: *const FrameBufferInfo = &info as *const FrameBufferInfo;
+        let ptr: *mut FrameBufferInfo = &mut info as *mut FrameBufferInfo;
 
         unsafe {
-            user_api::syscalls::ioctl(fd, 0, ptr as usize);
+            user_api::syscalls::ioctl(fd, 0, ptr as usize).expect("Could not get framebuffer info");
         }
 
-        let framebuffer = unsafe { user_api::syscalls::mmap(0, info.byte_len, fd) };
+        let framebuffer =
+            user_api::syscalls::mmap(0, info.byte_len, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0)
+                .expect("Could not map framebuffer");
 
         let back_buffer = alloc::vec![0; info.byte_len];
 
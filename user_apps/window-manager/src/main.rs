@@ -1,7 +1,9 @@
 #![no_std]
 #![no_main]
 
+use core::time::Duration;
 use event::mouseevent::MOUSE_EVENT;
+use user_api::syscalls::{clock_gettime, CLOCK_MONOTONIC};
 use world::WORLD;
 
 #[macro_use]
@@ -17,10 +19,13 @@ mod window;
 mod windowmanager;
 mod world;
 
+/// Shortest time between redraws, about 60 frames a second
+const FRAME_TIME: Duration = Duration::from_millis(16);
+
 #[no_mangle]
 fn main() {
-    windowmanager::WindowManager::new();
-    mouse::Mouse::new();
+    windowmanager::WindowManager::register();
+    mouse::Mouse::register();
 
     // let mut x = 0;
     // let mut y = 0;
@@ -28,9 +33,18 @@ fn main() {
     // let mut str_buf: [u8; 2] = [0; 2];
 
     loop {
+        let frame_start = clock_gettime(CLOCK_MONOTONIC).unwrap_or_default();
+        WORLD.lock().render();
+
+        // Blocks until the mouse moves, so nothing is redrawn while idle
         MOUSE_EVENT.lock().poll();
 
-        WORLD.lock().render();
+        // Sleep out the rest of the frame rather than redrawing for every
+        // packet. Movement in the meantime is queued and handled in one go.
+        let elapsed = clock_gettime(CLOCK_MONOTONIC).unwrap_or_default() - frame_start;
+        if let Some(remaining) = FRAME_TIME.checked_sub(elapsed) {
+            user_api::thread::sleep(remaining);
+        }
 
         // let bytes_read = unsafe { user_api::syscalls::read(0, &mut stdin_buf) };
 
@@ -1,6 +1,6 @@
 use crate::world::World;
 use alloc::sync::Arc;
-use spin::Mutex;
+use user_api::sync::Mutex;
 
 use crate::{
     event::mouseevent::{MouseEvent, MouseEventListener, MOUSE_EVENT},
@@ -16,7 +16,8 @@ use embedded_graphics::{
 pub struct Mouse {}
 
 impl Mouse {
-    pub fn new() {
+    /// Creates the mouse cursor and registers it for mouse events and drawing
+    pub fn register() {
         let x = Arc::new(Mutex::new(Self {}));
         MOUSE_EVENT.lock().register_listener(x.clone());
         WORLD.lock().register(x);
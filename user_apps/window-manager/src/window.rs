@@ -1,6 +1,6 @@
 use crate::world::World;
 use alloc::sync::Arc;
-use spin::Mutex;
+use user_api::sync::Mutex;
 
 use crate::{framebuffer::Display, world::FRAMEBUFFER};
 use embedded_graphics::{
@@ -18,16 +18,15 @@ pub struct Window {
 
 impl Window {
     pub fn new(x: i32, y: i32) -> Arc<Mutex<Window>> {
-        let window = Arc::new(Mutex::new(Self {
+        // MOUSE_EVENT.lock().register_listener(x.clone());
+        // WORLD.lock().register(window);
+
+        Arc::new(Mutex::new(Self {
             x,
             y,
             w: 500,
             h: 500,
-        }));
-        // MOUSE_EVENT.lock().register_listener(x.clone());
-        // WORLD.lock().register(window);
-
-        window
+        }))
     }
 
     pub fn get_location(&self) -> (i32, i32, u32, u32) {
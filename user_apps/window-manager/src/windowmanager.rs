@@ -1,7 +1,7 @@
 use crate::window::Window;
 use crate::world::World;
 use alloc::{sync::Arc, vec::Vec};
-use spin::Mutex;
+use user_api::sync::Mutex;
 
 use crate::{
     event::mouseevent::{MouseEvent, MouseEventListener, MOUSE_EVENT},
@@ -13,10 +13,9 @@ pub struct WindowManager {
 }
 
 impl WindowManager {
-    pub fn new() {
-        let mut windows = Vec::new();
-        windows.push(Window::new(100, 100));
-        windows.push(Window::new(650, 100));
+    /// Creates the window manager and registers it for mouse events and drawing
+    pub fn register() {
+        let windows = alloc::vec![Window::new(100, 100), Window::new(650, 100)];
         let windowmanager = Arc::new(Mutex::new(WindowManager { windows }));
 
         MOUSE_EVENT.lock().register_listener(windowmanager.clone());
@@ -1,6 +1,6 @@
 use alloc::{sync::Arc, vec::Vec};
 use lazy_static::lazy_static;
-use spin::Mutex;
+use user_api::sync::Mutex;
 
 use crate::framebuffer::{self, Display, FrameBuffer};
 use embedded_graphics::{
@@ -12,7 +12,8 @@ use embedded_graphics::{
 
 lazy_static! {
     pub static ref FRAMEBUFFER: Mutex<FrameBuffer> = {
-        let fd = unsafe { user_api::syscalls::open(b"/framebuffer/0\0") };
+        let fd = user_api::syscalls::open(b"/framebuffer/0\0", user_api::syscalls::O_RDWR)
+            .expect("Could not open framebuffer");
         Mutex::new(framebuffer::FrameBuffer::new(fd))
     };
     pub static ref WORLD: Mutex<World> = Mutex::new(World::new());